@@ -1,15 +1,64 @@
+use crate::media::{MediaConfig, UploadCache};
+use crate::responses::ResponseStore;
+use crate::transformers::ThoughtSignatureStore;
+use std::sync::Arc;
+
+/// How thought parts are rendered to the client
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum ThoughtMode {
+    /// Inline in `content`, wrapped in `<think></think>`
+    Think,
+    /// Inline in `content`, under markdown headers
+    Markdown,
+    /// In a separate `reasoning_content` field, leaving `content` clean
+    ReasoningContent,
+}
+
+impl ThoughtMode {
+    /// Parse the per request `thought_mode` field, accepting `reasoning_content` as well as `reasoning-content`
+    pub fn from_request(value: &str) -> Option<Self> {
+        <Self as clap::ValueEnum>::from_str(&value.replace('_', "-"), true).ok()
+    }
+}
+
+/// How image parts (inlineData) of a reply are rendered to the client
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum ImageOutput {
+    /// Appended to `content` as markdown images with a data URI
+    Markdown,
+    /// In a separate `images` array of `image_url` content parts
+    Images,
+}
+
+impl ImageOutput {
+    /// Parse the per request `image_output` field
+    pub fn from_request(value: &str) -> Option<Self> {
+        <Self as clap::ValueEnum>::from_str(value, true).ok()
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct AppState {
     pub upstream_url: String,
-    pub markdown_thought: bool,
+    pub thought_mode: ThoughtMode,
+    pub image_output: ImageOutput,
+    pub media: MediaConfig,
+    pub response_store: Arc<ResponseStore>,
+    pub upload_cache: Arc<UploadCache>,
+    pub thought_signatures: Arc<ThoughtSignatureStore>,
 }
 
 impl AppState {
-    pub fn new(upstream_url: String, markdown_thought: bool) -> Self {
+    pub fn new(upstream_url: String, thought_mode: ThoughtMode, image_output: ImageOutput, media: MediaConfig, response_store: ResponseStore) -> Self {
         Self {
             upstream_url,
-            markdown_thought,
+            thought_mode,
+            image_output,
+            media,
+            response_store: Arc::new(response_store),
+            upload_cache: Arc::new(UploadCache::default()),
+            thought_signatures: Arc::new(ThoughtSignatureStore::default()),
         }
     }
 }
@@ -0,0 +1,419 @@
+use crate::app_state::AppState;
+use crate::media::{mime_type_from_extension, MediaClient};
+use crate::transformers::transform_audio_to_google;
+use crate::utils::{extract_api_key, send_google_json};
+use actix_multipart::Multipart;
+use actix_web::{error::{ErrorBadRequest, ErrorInternalServerError, ErrorPayloadTooLarge}, web, Error, HttpRequest, HttpResponse};
+use awc::Client;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+
+/// Used when the request names no model
+const DEFAULT_TTS_MODEL: &str = "gemini-2.5-flash-preview-tts";
+
+/// Used when the request names no model or a Whisper one
+const DEFAULT_TRANSCRIPTION_MODEL: &str = "gemini-2.5-flash";
+
+/// Largest audio file accepted by the transcription endpoints
+const MAX_AUDIO_UPLOAD_SIZE: usize = 100 << 20;
+
+/// OpenAI voices and the Gemini prebuilt voice closest in character.
+/// Any other voice name is passed through, so Gemini voices can be asked for directly.
+const VOICE_MAP: [(&str, &str); 11] = [
+    ("alloy", "Zephyr"),
+    ("ash", "Orus"),
+    ("ballad", "Enceladus"),
+    ("coral", "Aoede"),
+    ("echo", "Charon"),
+    ("fable", "Puck"),
+    ("onyx", "Algenib"),
+    ("nova", "Kore"),
+    ("sage", "Iapetus"),
+    ("shimmer", "Leda"),
+    ("verse", "Achird"),
+];
+
+/// Gemini prebuilt voice for an OpenAI voice name, other names are passed through
+pub fn gemini_voice_name(voice: &str) -> &str {
+    VOICE_MAP.iter()
+        .find(|(openai_voice, _)| openai_voice.eq_ignore_ascii_case(voice))
+        .map_or(voice, |(_, gemini_voice)| gemini_voice)
+}
+
+/// Gemini audio output is 16-bit mono PCM, e.g. audio/L16;codec=pcm;rate=24000
+pub fn sample_rate_from_mime_type(mime_type: &str) -> u32 {
+    mime_type.split(';')
+        .filter_map(|param| param.trim().strip_prefix("rate="))
+        .find_map(|rate| rate.parse().ok())
+        .unwrap_or(24_000)
+}
+
+/// Wrap 16-bit little-endian mono PCM in a RIFF header
+pub fn pcm_to_wav(pcm: &[u8], sample_rate: u32) -> Vec<u8> {
+    let data_len = pcm.len() as u32;
+    let mut wav = Vec::with_capacity(44 + pcm.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    wav.extend_from_slice(pcm);
+    wav
+}
+
+/// Speak `text` with a Gemini TTS model, returning 16-bit mono PCM and its sample rate.
+/// An unsuccessful upstream reply is returned as `Ok(Err(response))` so it can be relayed as is.
+pub async fn synthesize_speech(
+    client: &Client,
+    upstream_url: &str,
+    api_key: &str,
+    model_name: &str,
+    text: &str,
+    voice: &str,
+) -> Result<Result<(Vec<u8>, u32), HttpResponse>, Error> {
+    let google_body = json!({
+        "contents": [{ "role": "user", "parts": [{ "text": text }] }],
+        "generationConfig": {
+            "responseModalities": ["AUDIO"],
+            "speechConfig": {
+                "voiceConfig": { "prebuiltVoiceConfig": { "voiceName": gemini_voice_name(voice) } }
+            }
+        }
+    });
+    let google_url = format!("{upstream_url}/models/{model_name}:generateContent?key={api_key}");
+    let google_response = match send_google_json(client.post(&google_url), Some(&google_body)).await? {
+        Ok(google_response) => google_response,
+        Err(upstream_error) => return Ok(Err(upstream_error)),
+    };
+
+    let inline_data = google_response["candidates"][0]["content"]["parts"].as_array()
+        .and_then(|parts| parts.iter().find_map(|part| part.get("inlineData")))
+        .ok_or_else(|| {
+            log::error!("No audio in Google reply: {google_response}");
+            ErrorInternalServerError("No audio was generated")
+        })?;
+    let pcm = inline_data.get("data").and_then(Value::as_str)
+        .and_then(|data| STANDARD.decode(data).ok())
+        .ok_or_else(|| ErrorInternalServerError("Failed to decode audio from Google"))?;
+    let sample_rate = sample_rate_from_mime_type(inline_data.get("mimeType").and_then(Value::as_str).unwrap_or(""));
+    Ok(Ok((pcm, sample_rate)))
+}
+
+/// Answer audio output chat requests: the text reply of each choice is spoken by DEFAULT_TTS_MODEL
+/// and moves from `content` to `message.audio.transcript`, as OpenAI audio replies have no content.
+/// `format` is wav or pcm16.
+pub async fn speak_chat_completion(
+    client: &Client,
+    upstream_url: &str,
+    api_key: &str,
+    voice: &str,
+    format: &str,
+    created: u64,
+    completion: &mut Value,
+) -> Result<Result<(), HttpResponse>, Error> {
+    let empty = vec![];
+    let choice_count = completion["choices"].as_array().unwrap_or(&empty).len();
+    for index in 0..choice_count {
+        let message = &mut completion["choices"][index]["message"];
+        let Some(transcript) = message["content"].as_str().filter(|t| !t.is_empty()).map(String::from) else {
+            continue;
+        };
+        let (pcm, sample_rate) = match synthesize_speech(client, upstream_url, api_key, DEFAULT_TTS_MODEL, &transcript, voice).await? {
+            Ok(speech) => speech,
+            Err(upstream_error) => return Ok(Err(upstream_error)),
+        };
+        let audio_data = if format == "wav" { pcm_to_wav(&pcm, sample_rate) } else { pcm };
+        message["content"] = Value::Null;
+        message["audio"] = json!({
+            "id": format!("audio_{}", uuid::Uuid::new_v4().simple()),
+            "data": STANDARD.encode(audio_data),
+            "transcript": transcript,
+            // Gemini keeps nothing to refer back to, this only mirrors OpenAI's one hour
+            "expires_at": created + 3600
+        });
+    }
+    Ok(Ok(()))
+}
+
+/// POST /v1/audio/speech
+pub async fn speech(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    log::info!("Got speech request: {}", String::from_utf8_lossy(&body_data));
+
+    let json_body: Value = serde_json::from_slice(&body_data)
+        .map_err(|_| ErrorBadRequest("Failed to parse JSON body"))?;
+    let api_key = extract_api_key(&req)
+        .ok_or_else(|| ErrorBadRequest("No API key provided"))?;
+    let model_name = json_body.get("model").and_then(Value::as_str)
+        .unwrap_or(DEFAULT_TTS_MODEL)
+        .trim_start_matches("models/");
+    let input = json_body.get("input").and_then(Value::as_str)
+        .ok_or_else(|| ErrorBadRequest("input must be a string"))?;
+    let voice = json_body.get("voice").and_then(Value::as_str).unwrap_or("alloy");
+    // Only containers that need no encoder are supported; OpenAI's mp3 default is replaced by wav
+    let response_format = json_body.get("response_format").and_then(Value::as_str).unwrap_or("wav");
+    if response_format != "wav" && response_format != "pcm" {
+        return Err(ErrorBadRequest(format!("Unsupported response_format: {response_format}, only wav and pcm are available")));
+    }
+    if json_body.get("speed").is_some() {
+        log::debug!("speed is not supported by Gemini TTS, ignored");
+    }
+    // Gemini TTS takes the speaking style as part of the prompt
+    let text = match json_body.get("instructions").and_then(Value::as_str).filter(|i| !i.is_empty()) {
+        Some(instructions) => format!("{instructions}: {input}"),
+        None => input.to_string(),
+    };
+
+    let (pcm, sample_rate) = match synthesize_speech(&client, &data.upstream_url, &api_key, model_name, &text, voice).await? {
+        Ok(speech) => speech,
+        Err(upstream_error) => return Ok(upstream_error),
+    };
+
+    if response_format == "pcm" {
+        Ok(HttpResponse::Ok().content_type("audio/pcm").body(pcm))
+    } else {
+        Ok(HttpResponse::Ok().content_type("audio/wav").body(pcm_to_wav(&pcm, sample_rate)))
+    }
+}
+
+/// The fields of a transcription or translation form
+#[derive(Default)]
+struct TranscriptionForm {
+    file: Option<(Vec<u8>, Option<String>, Option<String>)>,
+    model: Option<String>,
+    language: Option<String>,
+    prompt: Option<String>,
+    response_format: Option<String>,
+    temperature: Option<f64>,
+}
+
+async fn read_transcription_form(mut payload: Multipart) -> Result<TranscriptionForm, Error> {
+    let mut form = TranscriptionForm::default();
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+        let name = field.name().unwrap_or("").to_string();
+        let filename = field.content_disposition().and_then(|cd| cd.get_filename()).map(String::from);
+        let content_type = field.content_type().map(|mime| mime.essence_str().to_string());
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            bytes.extend_from_slice(&chunk?);
+            if bytes.len() > MAX_AUDIO_UPLOAD_SIZE {
+                return Err(ErrorPayloadTooLarge("Audio file is too large"));
+            }
+        }
+        let text = || String::from_utf8_lossy(&bytes).trim().to_string();
+        match name.as_str() {
+            "file" => form.file = Some((bytes, filename, content_type)),
+            "model" => form.model = Some(text()),
+            "language" => form.language = Some(text()).filter(|l| !l.is_empty()),
+            "prompt" => form.prompt = Some(text()).filter(|p| !p.is_empty()),
+            "response_format" => form.response_format = Some(text()),
+            "temperature" => form.temperature = text().parse().ok(),
+            other => log::debug!("Transcription form field {other} ignored"),
+        }
+    }
+    Ok(form)
+}
+
+// Browsers and SDKs often send application/octet-stream, fall back to the file extension
+fn audio_mime_type(filename: Option<&str>, content_type: Option<&str>) -> Result<String, Error> {
+    if let Some(content_type) = content_type.filter(|c| c.starts_with("audio/") || c.starts_with("video/")) {
+        return Ok(content_type.to_string());
+    }
+    filename.and_then(mime_type_from_extension)
+        .filter(|mime_type| mime_type.starts_with("audio/") || mime_type.starts_with("video/"))
+        .map(String::from)
+        .ok_or_else(|| ErrorBadRequest("Unsupported audio file, use mp3, wav, m4a, ogg, flac, webm, aac or aiff"))
+}
+
+// HH:MM:SS plus milliseconds, srt separates them with a comma and vtt with a dot
+fn format_timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}{separator}{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+fn segments_to_subtitles(segments: &[Value], vtt: bool) -> String {
+    let mut output = if vtt { "WEBVTT\n\n".to_string() } else { String::new() };
+    let separator = if vtt { '.' } else { ',' };
+    for (index, segment) in segments.iter().enumerate() {
+        let start = format_timestamp(segment["start"].as_f64().unwrap_or(0.0), separator);
+        let end = format_timestamp(segment["end"].as_f64().unwrap_or(0.0), separator);
+        let text = segment["text"].as_str().unwrap_or("").trim();
+        if !vtt {
+            output.push_str(&format!("{}\n", index + 1));
+        }
+        output.push_str(&format!("{start} --> {end}\n{text}\n\n"));
+    }
+    output
+}
+
+// Transcription (or translation to English) through a Gemini model reading the uploaded audio
+async fn transcribe(req: HttpRequest, payload: Multipart, data: &AppState, client: &Client, translate: bool) -> Result<HttpResponse, Error> {
+    let api_key = extract_api_key(&req)
+        .ok_or_else(|| ErrorBadRequest("No API key provided"))?;
+    let form = read_transcription_form(payload).await?;
+    let (audio, filename, content_type) = form.file
+        .ok_or_else(|| ErrorBadRequest("file is required"))?;
+    let mime_type = audio_mime_type(filename.as_deref(), content_type.as_deref())?;
+    let model_name = form.model.as_deref()
+        .filter(|model| !model.is_empty() && !model.starts_with("whisper"))
+        .unwrap_or(DEFAULT_TRANSCRIPTION_MODEL)
+        .trim_start_matches("models/");
+    let response_format = form.response_format.as_deref().unwrap_or("json");
+    let with_segments = match response_format {
+        "json" | "text" => false,
+        "srt" | "vtt" | "verbose_json" => true,
+        other => return Err(ErrorBadRequest(format!("Unsupported response_format: {other}"))),
+    };
+    log::info!("Got {} request for {} bytes of {mime_type}, response_format: {response_format}", if translate { "translation" } else { "transcription" }, audio.len());
+
+    let mut instruction = if translate {
+        "Translate the speech in this audio into English. Reply with the English translation only.".to_string()
+    } else {
+        "Transcribe the speech in this audio verbatim, in the language it is spoken. Reply with the transcript only.".to_string()
+    };
+    if let Some(language) = form.language.as_deref().filter(|_| !translate) {
+        instruction.push_str(&format!(" The audio is in the language with ISO-639-1 code {language}."));
+    }
+    if with_segments {
+        instruction.push_str(" Split it into segments of at most a sentence, with start and end times in seconds from the beginning of the audio.");
+    }
+    if let Some(prompt) = &form.prompt {
+        instruction.push_str(&format!(" Context for spelling and style, not to be transcribed: {prompt}"));
+    }
+
+    // Video containers are read as they are, audio Gemini does not know may need transcoding
+    let (mime_type, audio) = if mime_type.starts_with("audio/") {
+        data.media.prepare_audio(mime_type, audio).await?
+    } else {
+        (mime_type, audio)
+    };
+    let media_client = req.app_data::<web::Data<MediaClient>>()
+        .ok_or_else(|| ErrorInternalServerError("No media client configured"))?;
+    let audio_part = transform_audio_to_google(client, media_client, &api_key, data, audio, &mime_type).await?;
+    let mut generation_config = json!({});
+    if let Some(temperature) = form.temperature {
+        generation_config["temperature"] = json!(temperature);
+    }
+    if with_segments {
+        generation_config["responseMimeType"] = json!("application/json");
+        generation_config["responseSchema"] = json!({
+            "type": "object",
+            "properties": {
+                "language": { "type": "string" },
+                "segments": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "start": { "type": "number" },
+                            "end": { "type": "number" },
+                            "text": { "type": "string" }
+                        },
+                        "required": ["start", "end", "text"]
+                    }
+                }
+            },
+            "required": ["segments"]
+        });
+    }
+    let google_body = json!({
+        "contents": [{
+            "role": "user",
+            "parts": [
+                { "text": instruction },
+                audio_part
+            ]
+        }],
+        "generationConfig": generation_config
+    });
+    let google_url = format!("{}/models/{model_name}:generateContent?key={api_key}", data.upstream_url);
+    let google_response = match send_google_json(client.post(&google_url), Some(&google_body)).await? {
+        Ok(google_response) => google_response,
+        Err(upstream_error) => return Ok(upstream_error),
+    };
+
+    let empty = vec![];
+    let reply: String = google_response["candidates"][0]["content"]["parts"].as_array().unwrap_or(&empty).iter()
+        .filter(|part| part.get("thought").and_then(Value::as_bool) != Some(true))
+        .filter_map(|part| part.get("text").and_then(Value::as_str))
+        .collect();
+    let usage = &google_response["usageMetadata"];
+    let input_tokens = usage["promptTokenCount"].as_i64().unwrap_or(0);
+    let output_tokens = usage["candidatesTokenCount"].as_i64().unwrap_or(0) + usage["thoughtsTokenCount"].as_i64().unwrap_or(0);
+
+    if !with_segments {
+        let text = reply.trim();
+        return Ok(match response_format {
+            "text" => HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(text.to_string()),
+            _ => HttpResponse::Ok().json(json!({
+                "text": text,
+                "usage": {
+                    "type": "tokens",
+                    "input_tokens": input_tokens,
+                    "output_tokens": output_tokens,
+                    "total_tokens": input_tokens + output_tokens
+                }
+            })),
+        });
+    }
+
+    let transcript: Value = serde_json::from_str(&reply).map_err(|e| {
+        log::error!("Failed to parse segments from Google reply: {e}, reply: {reply}");
+        ErrorInternalServerError("Failed to parse transcription segments")
+    })?;
+    let segments = transcript["segments"].as_array().unwrap_or(&empty);
+    Ok(match response_format {
+        "srt" => HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(segments_to_subtitles(segments, false)),
+        "vtt" => HttpResponse::Ok().content_type("text/vtt; charset=utf-8").body(segments_to_subtitles(segments, true)),
+        _ => {
+            let text: Vec<&str> = segments.iter().filter_map(|s| s["text"].as_str()).map(str::trim).collect();
+            let verbose_segments: Vec<Value> = segments.iter().enumerate().map(|(id, segment)| json!({
+                "id": id,
+                "start": segment["start"],
+                "end": segment["end"],
+                "text": segment["text"]
+            })).collect();
+            HttpResponse::Ok().json(json!({
+                "task": if translate { "translate" } else { "transcribe" },
+                "language": if translate { json!("english") } else { transcript.get("language").cloned().or(form.language.map(Value::from)).unwrap_or(Value::Null) },
+                "duration": segments.last().and_then(|s| s["end"].as_f64()).unwrap_or(0.0),
+                "text": text.join(" "),
+                "segments": verbose_segments
+            }))
+        }
+    })
+}
+
+/// POST /v1/audio/transcriptions
+pub async fn transcriptions(
+    req: HttpRequest,
+    payload: Multipart,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    transcribe(req, payload, &data, &client, false).await
+}
+
+/// POST /v1/audio/translations
+pub async fn translations(
+    req: HttpRequest,
+    payload: Multipart,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    transcribe(req, payload, &data, &client, true).await
+}
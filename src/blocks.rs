@@ -0,0 +1,108 @@
+use serde_json::Value;
+
+/// Kind of a block that text deltas accumulate into
+#[derive(Clone, Copy, PartialEq)]
+pub enum TextKind {
+    Reasoning,
+    Text,
+}
+
+/// One piece of assistant output
+pub enum Block {
+    Text { kind: TextKind, text: String },
+    ToolCall { id: Value, name: Value, arguments: String },
+}
+
+/// Change to the block list, by block index; each front end names it in its own events
+pub enum BlockEvent {
+    Opened(usize),
+    Delta(usize, String),
+    Closed(usize),
+    /// A tool call block, opened and closed at once
+    ToolCall(usize),
+}
+
+/// Accumulates chat deltas into the content blocks shared by the Messages and Responses front ends.
+/// Non-stream replies go through it too, with the events discarded.
+pub struct BlockAccumulator {
+    pub blocks: Vec<Block>,
+    /// Whether the last block still takes deltas
+    open: bool,
+    pub finish_reason: Option<String>,
+    skip_reasoning: bool,
+}
+
+impl BlockAccumulator {
+    pub fn new(skip_reasoning: bool) -> Self {
+        Self {
+            blocks: Vec::new(),
+            open: false,
+            finish_reason: None,
+            skip_reasoning,
+        }
+    }
+
+    /// Apply one chat message or delta; only the first choice is used
+    pub fn push_chat_choice(&mut self, choice: &Value, message_key: &str) -> Vec<BlockEvent> {
+        let message = &choice[message_key];
+        let mut events = Vec::new();
+        if let Some(reasoning) = message.get("reasoning_content").and_then(Value::as_str).filter(|r| !r.is_empty()) {
+            if !self.skip_reasoning {
+                self.push_text(TextKind::Reasoning, reasoning, &mut events);
+            }
+        }
+        if let Some(text) = message.get("content").and_then(Value::as_str).filter(|t| !t.is_empty()) {
+            self.push_text(TextKind::Text, text, &mut events);
+        }
+        if let Some(tool_calls) = message.get("tool_calls").and_then(Value::as_array) {
+            for tool_call in tool_calls {
+                self.push_tool_call(tool_call, &mut events);
+            }
+        }
+        if let Some(finish_reason) = choice.get("finish_reason").and_then(Value::as_str) {
+            self.finish_reason = Some(finish_reason.to_string());
+        }
+        events
+    }
+
+    /// Close the block still taking deltas, if any
+    pub fn close(&mut self) -> Vec<BlockEvent> {
+        let mut events = Vec::new();
+        self.close_into(&mut events);
+        events
+    }
+
+    fn close_into(&mut self, events: &mut Vec<BlockEvent>) {
+        if std::mem::take(&mut self.open) {
+            events.push(BlockEvent::Closed(self.blocks.len() - 1));
+        }
+    }
+
+    fn push_text(&mut self, kind: TextKind, delta: &str, events: &mut Vec<BlockEvent>) {
+        let open_kind = match self.blocks.last() {
+            Some(Block::Text { kind, .. }) if self.open => Some(*kind),
+            _ => None,
+        };
+        if open_kind != Some(kind) {
+            self.close_into(events);
+            self.blocks.push(Block::Text { kind, text: String::new() });
+            self.open = true;
+            events.push(BlockEvent::Opened(self.blocks.len() - 1));
+        }
+        if let Some(Block::Text { text, .. }) = self.blocks.last_mut() {
+            text.push_str(delta);
+        }
+        events.push(BlockEvent::Delta(self.blocks.len() - 1, delta.to_string()));
+    }
+
+    // Gemini sends whole function calls, so each one opens and closes in one go
+    fn push_tool_call(&mut self, tool_call: &Value, events: &mut Vec<BlockEvent>) {
+        self.close_into(events);
+        self.blocks.push(Block::ToolCall {
+            id: tool_call["id"].clone(),
+            name: tool_call["function"]["name"].clone(),
+            arguments: tool_call["function"]["arguments"].as_str().unwrap_or("{}").to_string(),
+        });
+        events.push(BlockEvent::ToolCall(self.blocks.len() - 1));
+    }
+}
@@ -1,4 +1,6 @@
+use crate::app_state::{ImageOutput, ThoughtMode};
 use clap::Parser;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -7,6 +9,31 @@ pub struct Args {
     pub port: u16,
     #[arg(long, value_name = "upstream_url", default_value = "https://generativelanguage.googleapis.com/v1alpha")]
     pub upstream_url: String,
+    /// Same as --thought-mode markdown
     #[arg(long, value_name = "markdown_thought")]
     pub markdown_thought: bool,
+    #[arg(long, value_name = "thought_mode", value_enum, default_value_t = ThoughtMode::Think)]
+    pub thought_mode: ThoughtMode,
+    /// How images generated by image output models are returned in chat replies
+    #[arg(long, value_name = "image_output", value_enum, default_value_t = ImageOutput::Markdown)]
+    pub image_output: ImageOutput,
+    /// Media up to this many bytes is sent inline, larger media is uploaded to the Files API.
+    /// Gemini refuses requests over 20 MB in total, so keep it well below that
+    #[arg(long, value_name = "inline_media_max_bytes", default_value_t = 4 << 20)]
+    pub inline_media_max_bytes: usize,
+    /// ffmpeg binary used to transcode input audio in formats Gemini does not read (e.g. webm, m4a) to FLAC
+    #[arg(long, value_name = "ffmpeg_path")]
+    pub ffmpeg_path: Option<PathBuf>,
+    /// Largest remote file, in bytes, downloaded for an http(s) image_url or file_data URL
+    #[arg(long, value_name = "remote_media_max_bytes", default_value_t = 20 << 20)]
+    pub remote_media_max_bytes: usize,
+    /// Comma separated hosts image_url and file_data URLs may be downloaded from, any public host when empty
+    #[arg(long, value_name = "remote_media_allow_hosts", value_delimiter = ',')]
+    pub remote_media_allow_hosts: Vec<String>,
+    /// Comma separated hosts image_url and file_data URLs are never downloaded from
+    #[arg(long, value_name = "remote_media_deny_hosts", value_delimiter = ',')]
+    pub remote_media_deny_hosts: Vec<String>,
+    /// Also keep /v1/responses conversations in this directory so previous_response_id survives restarts
+    #[arg(long, value_name = "responses_store_dir")]
+    pub responses_store_dir: Option<PathBuf>,
 }
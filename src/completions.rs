@@ -0,0 +1,157 @@
+use crate::app_state::AppState;
+use crate::proxy::{
+    build_google_request, receive_google_response, relay_google_stream, response_builder_from, send_google_request, unix_timestamp, ChunkEncoder,
+};
+use crate::transformers::StreamState;
+use actix_web::{error::ErrorBadRequest, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use awc::Client;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+// Request fields shared with chat completions, mapped by transform_openai_to_google
+const SHARED_FIELDS: [&str; 11] = [
+    "model",
+    "stream",
+    "stream_options",
+    "max_tokens",
+    "stop",
+    "n",
+    "temperature",
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "reasoning_effort",
+];
+
+fn prompt_from_request(prompt: Option<&Value>) -> Result<Vec<String>, Error> {
+    match prompt {
+        Some(Value::String(text)) => Ok(vec![text.clone()]),
+        Some(Value::Array(items)) => items.iter()
+            .map(|item| item.as_str()
+                .map(|text| text.to_string())
+                .ok_or_else(|| ErrorBadRequest("Only string prompts are supported, token arrays are not")))
+            .collect(),
+        _ => Err(ErrorBadRequest("prompt must be a string or an array of strings")),
+    }
+}
+
+// Wrap the prompt into a single user turn of a chat completions body
+fn completion_to_chat_request(json_body: &Value, prompt: &[String]) -> Value {
+    let mut instruction = "Continue the text given by the user. Reply with the continuation only, without repeating the given text.".to_string();
+    if let Some(suffix) = json_body.get("suffix").and_then(Value::as_str).filter(|s| !s.is_empty()) {
+        instruction.push_str(&format!(" The continuation is inserted right before the following text and must lead into it:\n{suffix}"));
+    }
+    let prompt_parts: Vec<Value> = prompt.iter().map(|text| json!({ "type": "text", "text": text })).collect();
+    let mut chat_body = json!({
+        "messages": [
+            { "role": "system", "content": instruction },
+            { "role": "user", "content": prompt_parts }
+        ],
+        // Keeps thoughts out of the completion text
+        "thought_mode": "reasoning_content"
+    });
+    for field in SHARED_FIELDS {
+        if let Some(value) = json_body.get(field) {
+            chat_body[field] = value.clone();
+        }
+    }
+    chat_body
+}
+
+// Convert a chat.completion (or chunk) into a text_completion
+fn chat_to_text_completion(chat: &Value, echo: Option<&str>, echoed: &mut HashSet<u64>) -> Value {
+    let empty = vec![];
+    let message_key = if chat.get("object").and_then(Value::as_str) == Some("chat.completion.chunk") { "delta" } else { "message" };
+    let choices: Vec<Value> = chat.get("choices").and_then(Value::as_array).unwrap_or(&empty).iter().map(|choice| {
+        let index = choice.get("index").and_then(Value::as_u64).unwrap_or(0);
+        let mut text = choice[message_key].get("content").and_then(Value::as_str).unwrap_or("").to_string();
+        if let Some(echo) = echo {
+            if echoed.insert(index) {
+                text.insert_str(0, echo);
+            }
+        }
+        json!({
+            "text": text,
+            "index": index,
+            "logprobs": null,
+            "finish_reason": choice.get("finish_reason").cloned().unwrap_or(Value::Null)
+        })
+    }).collect();
+    let mut completion = json!({
+        "id": chat["id"],
+        "object": "text_completion",
+        "created": chat["created"],
+        "model": chat["model"],
+        "choices": choices
+    });
+    if let Some(usage) = chat.get("usage") {
+        completion["usage"] = usage.clone();
+    }
+    completion
+}
+
+/// Text completions stream on top of chat.completion.chunk values
+struct CompletionChunkEncoder {
+    echo: Option<String>,
+    echoed: HashSet<u64>,
+}
+
+impl ChunkEncoder for CompletionChunkEncoder {
+    fn encode(&mut self, chunk: Value) -> String {
+        if chunk.get("error").is_some() {
+            return format!("data: {chunk}\n\n");
+        }
+        let completion = chat_to_text_completion(&chunk, self.echo.as_deref(), &mut self.echoed);
+        // Role-only and reasoning-only deltas carry nothing for a text completion
+        let has_content = completion.get("usage").is_some()
+            || completion["choices"].as_array().is_some_and(|choices| choices.iter()
+                .any(|c| c["text"].as_str().is_some_and(|t| !t.is_empty()) || !c["finish_reason"].is_null()));
+        if has_content {
+            format!("data: {completion}\n\n")
+        } else {
+            String::new()
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        "data: [DONE]\n\n".to_string()
+    }
+}
+
+/// POST /v1/completions
+pub async fn completions(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    log::info!("Got completions request: {}", String::from_utf8_lossy(&body_data));
+
+    let json_body: Value = serde_json::from_slice(&body_data)
+        .map_err(|_| ErrorBadRequest("Failed to parse JSON body"))?;
+    let prompt = prompt_from_request(json_body.get("prompt"))?;
+    let echo = json_body.get("echo").and_then(Value::as_bool).unwrap_or(false)
+        .then(|| prompt.concat());
+
+    let chat_body = completion_to_chat_request(&json_body, &prompt);
+    let google_request = build_google_request(&req, &chat_body, &data, &client).await?;
+    let upstream_response = send_google_request(&google_request, &data, &client).await?;
+
+    let mut state = StreamState::new(format!("cmpl-{}", uuid::Uuid::new_v4().simple()), unix_timestamp(), data.thought_signatures.clone());
+    state.include_usage = google_request.include_usage;
+
+    if upstream_response.content_type().contains("text/event-stream") {
+        let encoder = CompletionChunkEncoder { echo, echoed: HashSet::new() };
+        let mut response = response_builder_from(&upstream_response);
+        Ok(response.streaming(relay_google_stream(upstream_response, &google_request, state, encoder)))
+    } else {
+        match receive_google_response(upstream_response, &google_request, &data.upload_cache, &mut state).await? {
+            Ok(chat_response) => {
+                let completion = chat_to_text_completion(&chat_response, echo.as_deref(), &mut HashSet::new());
+                log::info!("Replied to client: {completion}");
+                Ok(HttpResponse::Ok().json(completion))
+            },
+            Err(upstream_error) => Ok(upstream_error),
+        }
+    }
+}
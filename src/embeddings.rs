@@ -0,0 +1,113 @@
+use crate::app_state::AppState;
+use crate::utils::{extract_api_key, send_google_json};
+use actix_web::{error::ErrorBadRequest, web, Error, HttpRequest, HttpResponse};
+use awc::Client;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::future::try_join_all;
+use serde_json::{json, Value};
+
+/// Most requests Gemini takes in one batchEmbedContents call
+const MAX_BATCH_REQUESTS: usize = 100;
+
+// OpenAI accepts a string, an array of strings or token arrays; Gemini only embeds text
+fn inputs_from_request(input: Option<&Value>) -> Result<Vec<String>, Error> {
+    match input {
+        Some(Value::String(text)) => Ok(vec![text.clone()]),
+        Some(Value::Array(items)) if !items.is_empty() => items.iter()
+            .map(|item| item.as_str()
+                .map(|text| text.to_string())
+                .ok_or_else(|| ErrorBadRequest("Only string inputs are supported, token arrays are not")))
+            .collect(),
+        _ => Err(ErrorBadRequest("input must be a string or a non-empty array of strings")),
+    }
+}
+
+// OpenAI's base64 encoding_format is the little-endian float32 array
+fn encode_embedding(values: &[Value], base64_format: bool) -> Value {
+    if base64_format {
+        let bytes: Vec<u8> = values.iter()
+            .filter_map(Value::as_f64)
+            .flat_map(|v| (v as f32).to_le_bytes())
+            .collect();
+        json!(STANDARD.encode(bytes))
+    } else {
+        json!(values)
+    }
+}
+
+/// POST /v1/embeddings
+pub async fn embeddings(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    log::info!("Got embeddings request: {}", String::from_utf8_lossy(&body_data));
+
+    let json_body: Value = serde_json::from_slice(&body_data)
+        .map_err(|_| ErrorBadRequest("Failed to parse JSON body"))?;
+    let api_key = extract_api_key(&req)
+        .ok_or_else(|| ErrorBadRequest("No API key provided"))?;
+    let model_name_in_request = json_body["model"].as_str()
+        .ok_or_else(|| ErrorBadRequest("Model not found in request"))?;
+    let model_name = model_name_in_request.trim_start_matches("models/");
+
+    let inputs = inputs_from_request(json_body.get("input"))?;
+    let dimensions = json_body.get("dimensions").and_then(Value::as_i64);
+    let base64_format = match json_body.get("encoding_format").and_then(Value::as_str) {
+        None | Some("float") => false,
+        Some("base64") => true,
+        Some(other) => return Err(ErrorBadRequest(format!("Unsupported encoding_format: {other}"))),
+    };
+
+    let requests: Vec<Value> = inputs.iter().map(|text| {
+        let mut request = json!({
+            "model": format!("models/{model_name}"),
+            "content": { "parts": [{ "text": text }] }
+        });
+        if let Some(dimensions) = dimensions {
+            request["outputDimensionality"] = json!(dimensions);
+        }
+        request
+    }).collect();
+
+    // Gemini takes at most 100 requests per batch, OpenAI clients send up to 2048 inputs
+    let google_url = format!("{}/models/{model_name}:batchEmbedContents?key={api_key}", data.upstream_url);
+    let batch_bodies: Vec<Value> = requests.chunks(MAX_BATCH_REQUESTS).map(|batch| json!({ "requests": batch })).collect();
+    let batch_responses = try_join_all(batch_bodies.iter().map(|body| send_google_json(client.post(&google_url), Some(body)))).await?;
+
+    let empty = vec![];
+    let mut openai_data = Vec::new();
+    let mut prompt_tokens = 0;
+    for batch_response in batch_responses {
+        let google_response = match batch_response {
+            Ok(google_response) => google_response,
+            Err(upstream_error) => return Ok(upstream_error),
+        };
+        let embeddings = google_response.get("embeddings").and_then(Value::as_array).unwrap_or(&empty);
+        for embedding in embeddings {
+            let values = embedding.get("values").and_then(Value::as_array).unwrap_or(&empty);
+            openai_data.push(json!({
+                "object": "embedding",
+                "index": openai_data.len(),
+                "embedding": encode_embedding(values, base64_format)
+            }));
+        }
+        // batchEmbedContents does not always report token usage
+        prompt_tokens += google_response.get("usageMetadata")
+            .and_then(|u| u.get("promptTokenCount"))
+            .and_then(Value::as_i64)
+            .unwrap_or(0);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "object": "list",
+        "data": openai_data,
+        "model": model_name_in_request,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "total_tokens": prompt_tokens
+        }
+    })))
+}
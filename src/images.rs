@@ -0,0 +1,166 @@
+use crate::app_state::AppState;
+use crate::proxy::unix_timestamp;
+use crate::utils::{extract_api_key, send_google_json};
+use actix_web::{error::ErrorBadRequest, web, Error, HttpRequest, HttpResponse};
+use awc::Client;
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+
+/// Used when the request names no model
+const DEFAULT_IMAGE_MODEL: &str = "imagen-3.0-generate-002";
+
+/// Aspect ratios accepted by Imagen and the Gemini image models
+const ASPECT_RATIOS: [(&str, f64); 5] = [("1:1", 1.0), ("3:4", 0.75), ("4:3", 4.0 / 3.0), ("9:16", 9.0 / 16.0), ("16:9", 16.0 / 9.0)];
+
+// OpenAI sizes are WIDTHxHEIGHT, Google takes the nearest aspect ratio
+fn aspect_ratio_from_size(size: &str) -> Result<Option<&'static str>, Error> {
+    if size == "auto" {
+        return Ok(None);
+    }
+    let (width, height) = size.split_once('x')
+        .and_then(|(w, h)| Some((w.parse::<f64>().ok()?, h.parse::<f64>().ok()?)))
+        .filter(|(w, h)| *w > 0.0 && *h > 0.0)
+        .ok_or_else(|| ErrorBadRequest(format!("Invalid size: {size}")))?;
+    let ratio = width / height;
+    Ok(ASPECT_RATIOS.iter()
+        .min_by(|a, b| (a.1 - ratio).abs().total_cmp(&(b.1 - ratio).abs()))
+        .map(|(name, _)| *name))
+}
+
+fn openai_image(mime_type: &str, base64_data: &str, as_url: bool, revised_prompt: Option<&str>) -> Value {
+    // No storage to host the images, so url is a data URI
+    let mut image = if as_url {
+        json!({ "url": format!("data:{mime_type};base64,{base64_data}") })
+    } else {
+        json!({ "b64_json": base64_data })
+    };
+    if let Some(revised_prompt) = revised_prompt {
+        image["revised_prompt"] = json!(revised_prompt);
+    }
+    image
+}
+
+// Imagen generates all n images in one predict call
+async fn generate_with_imagen(
+    client: &Client,
+    google_url: &str,
+    prompt: &str,
+    n: i64,
+    aspect_ratio: Option<&str>,
+    as_url: bool,
+) -> Result<Result<Vec<Value>, HttpResponse>, Error> {
+    let mut parameters = json!({ "sampleCount": n });
+    if let Some(aspect_ratio) = aspect_ratio {
+        parameters["aspectRatio"] = json!(aspect_ratio);
+    }
+    let body = json!({ "instances": [{ "prompt": prompt }], "parameters": parameters });
+    let google_response = match send_google_json(client.post(google_url), Some(&body)).await? {
+        Ok(google_response) => google_response,
+        Err(upstream_error) => return Ok(Err(upstream_error)),
+    };
+    let empty = vec![];
+    Ok(Ok(google_response.get("predictions").and_then(Value::as_array).unwrap_or(&empty).iter()
+        .filter_map(|prediction| {
+            let data = prediction.get("bytesBase64Encoded").and_then(Value::as_str)?;
+            let mime_type = prediction.get("mimeType").and_then(Value::as_str).unwrap_or("image/png");
+            Some(openai_image(mime_type, data, as_url, prediction.get("prompt").and_then(Value::as_str)))
+        })
+        .collect()))
+}
+
+// Gemini image models return one image per generateContent call, so n calls run concurrently
+async fn generate_with_gemini(
+    client: &Client,
+    google_url: &str,
+    prompt: &str,
+    n: i64,
+    aspect_ratio: Option<&str>,
+    as_url: bool,
+) -> Result<Result<Vec<Value>, HttpResponse>, Error> {
+    let mut generation_config = json!({ "responseModalities": ["TEXT", "IMAGE"] });
+    if let Some(aspect_ratio) = aspect_ratio {
+        generation_config["imageConfig"] = json!({ "aspectRatio": aspect_ratio });
+    }
+    let body = json!({
+        "contents": [{ "role": "user", "parts": [{ "text": prompt }] }],
+        "generationConfig": generation_config
+    });
+    let replies = join_all((0..n).map(|_| send_google_json(client.post(google_url), Some(&body)))).await;
+
+    let mut images = Vec::new();
+    for reply in replies {
+        let google_response = match reply? {
+            Ok(google_response) => google_response,
+            Err(upstream_error) => return Ok(Err(upstream_error)),
+        };
+        let empty = vec![];
+        let parts = google_response["candidates"][0]["content"]["parts"].as_array().unwrap_or(&empty);
+        // Text next to the image is the model's take on the prompt
+        let text: String = parts.iter().filter_map(|part| part.get("text").and_then(Value::as_str)).collect();
+        let revised_prompt = Some(text.as_str()).filter(|t| !t.is_empty());
+        images.extend(parts.iter().filter_map(|part| {
+            let inline_data = part.get("inlineData")?;
+            let data = inline_data.get("data").and_then(Value::as_str)?;
+            let mime_type = inline_data.get("mimeType").and_then(Value::as_str).unwrap_or("image/png");
+            Some(openai_image(mime_type, data, as_url, revised_prompt))
+        }));
+    }
+    Ok(Ok(images))
+}
+
+/// POST /v1/images/generations
+pub async fn image_generations(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    log::info!("Got image generations request: {}", String::from_utf8_lossy(&body_data));
+
+    let json_body: Value = serde_json::from_slice(&body_data)
+        .map_err(|_| ErrorBadRequest("Failed to parse JSON body"))?;
+    let api_key = extract_api_key(&req)
+        .ok_or_else(|| ErrorBadRequest("No API key provided"))?;
+    let model_name = json_body.get("model").and_then(Value::as_str)
+        .unwrap_or(DEFAULT_IMAGE_MODEL)
+        .trim_start_matches("models/");
+    let prompt = json_body.get("prompt").and_then(Value::as_str)
+        .ok_or_else(|| ErrorBadRequest("prompt must be a string"))?;
+    // Imagen caps sampleCount at 4, Gemini image models are called once per image
+    let is_imagen = model_name.starts_with("imagen");
+    let max_n = if is_imagen { 4 } else { 10 };
+    let n = json_body.get("n").and_then(Value::as_i64).unwrap_or(1);
+    if !(1..=max_n).contains(&n) {
+        return Err(ErrorBadRequest(format!("n must be between 1 and {max_n} for {model_name}")));
+    }
+    let aspect_ratio = match json_body.get("size").and_then(Value::as_str) {
+        Some(size) => aspect_ratio_from_size(size)?,
+        None => None,
+    };
+    let as_url = match json_body.get("response_format").and_then(Value::as_str) {
+        None | Some("b64_json") => false,
+        Some("url") => true,
+        Some(other) => return Err(ErrorBadRequest(format!("Unsupported response_format: {other}"))),
+    };
+
+    let generated = if is_imagen {
+        let google_url = format!("{}/models/{model_name}:predict?key={api_key}", data.upstream_url);
+        generate_with_imagen(&client, &google_url, prompt, n, aspect_ratio, as_url).await?
+    } else {
+        let google_url = format!("{}/models/{model_name}:generateContent?key={api_key}", data.upstream_url);
+        generate_with_gemini(&client, &google_url, prompt, n, aspect_ratio, as_url).await?
+    };
+    let images = match generated {
+        Ok(images) => images,
+        Err(upstream_error) => return Ok(upstream_error),
+    };
+    if images.is_empty() {
+        log::error!("No image in Google reply, it may have been filtered");
+        return Err(ErrorBadRequest("No image was generated, the prompt may have been blocked"));
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "created": unix_timestamp(),
+        "data": images
+    })))
+}
@@ -1,20 +1,48 @@
 mod app_state;
+mod audio;
+mod blocks;
 mod cli;
+mod completions;
+mod embeddings;
+mod images;
+mod media;
+mod messages;
+mod models;
 mod proxy;
+mod responses;
+mod schema;
+mod sse;
 mod transformers;
 mod utils;
 
 use actix_web::{web::{self, PayloadConfig}, App, HttpServer};
+use app_state::{ImageOutput, ThoughtMode};
+use audio::{speech, transcriptions, translations};
 use cli::Args;
 use clap::Parser;
+use completions::completions;
+use embeddings::embeddings;
+use images::image_generations;
+use messages::messages;
+use models::{get_model, list_models};
 use proxy::reverse_proxy;
+use responses::{responses, ResponseStore};
 use utils::{new_request_client, tls_config};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
     let args = Args::parse();
-    let state = app_state::AppState::new(args.upstream_url, args.markdown_thought);
+    let thought_mode = if args.markdown_thought { ThoughtMode::Markdown } else { args.thought_mode };
+    let response_store = ResponseStore::new(args.responses_store_dir);
+    let media = media::MediaConfig {
+        max_remote_bytes: args.remote_media_max_bytes,
+        allow_hosts: args.remote_media_allow_hosts,
+        deny_hosts: args.remote_media_deny_hosts,
+        inline_max_bytes: args.inline_media_max_bytes,
+        ffmpeg_path: args.ffmpeg_path,
+    };
+    let state = app_state::AppState::new(args.upstream_url, thought_mode, args.image_output, media, response_store);
     let tls_client_config = std::sync::Arc::new(tls_config());
 
     // Test
@@ -49,17 +77,33 @@ async fn main() -> std::io::Result<()> {
         "modelVersion": "gemini-1.5-flash-001",
         "created": 1653500834
     });
-    let openai_response = transformers::transform_google_to_openai(&google_input, false, false, true, false);
+    let mut stream_state = transformers::StreamState {
+        last_is_thought: true,
+        ..transformers::StreamState::new(transformers::new_chat_completion_id(), 1653500834, Default::default())
+    };
+    let openai_response = transformers::transform_google_to_openai(&google_input, false, false, ThoughtMode::Think, ImageOutput::Markdown, &mut stream_state);
     log::debug!("{openai_response:?}");
 
     HttpServer::new(move || {
         let client = new_request_client(tls_client_config.clone());
+        let media_client = media::MediaClient::new(tls_client_config.clone(), &state.media);
 
         App::new()
             .app_data(PayloadConfig::new(1 << 31)) // for loading big pic
 //          .wrap(actix_web::middleware::Compress::default()) // breaks streaming
             .app_data(web::Data::new(state.clone()))
             .app_data(web::Data::new(client))
+            .app_data(web::Data::new(media_client))
+            .route("/v1/models", web::get().to(list_models))
+            .route("/v1/models/{model}", web::get().to(get_model))
+            .route("/v1/embeddings", web::post().to(embeddings))
+            .route("/v1/completions", web::post().to(completions))
+            .route("/v1/responses", web::post().to(responses))
+            .route("/v1/messages", web::post().to(messages))
+            .route("/v1/images/generations", web::post().to(image_generations))
+            .route("/v1/audio/speech", web::post().to(speech))
+            .route("/v1/audio/transcriptions", web::post().to(transcriptions))
+            .route("/v1/audio/translations", web::post().to(translations))
             .route("/{path:.*}", web::to(reverse_proxy))
     })
     .bind(format!("0.0.0.0:{}", args.port))?
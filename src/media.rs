@@ -0,0 +1,541 @@
+use actix_tls::connect::{Connector as TcpConnector, Resolve, Resolver};
+use actix_web::{error::{ErrorBadRequest, ErrorInternalServerError}, http::Uri, Error};
+use awc::{Client, Connector};
+use futures_util::future::LocalBoxFuture;
+use rustls::ClientConfig;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Gemini Files API URIs, `files/<id>` in a request is expanded to this prefix
+pub const GOOGLE_FILES_URI_PREFIX: &str = "https://generativelanguage.googleapis.com/v1beta/files/";
+
+/// Audio MIME types Gemini accepts, anything else is transcoded when ffmpeg is configured
+const GEMINI_AUDIO_MIME_TYPES: [&str; 9] = [
+    "audio/wav", "audio/x-wav", "audio/mp3", "audio/mpeg", "audio/aiff", "audio/aac", "audio/ogg", "audio/flac", "audio/x-flac",
+];
+
+/// Longest an ffmpeg transcode may take
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Limits for media the adapter downloads on behalf of the client
+#[derive(Clone, Debug)]
+pub struct MediaConfig {
+    /// Largest remote file fetched for an `image_url` or `file` part
+    pub max_remote_bytes: usize,
+    /// When not empty, only these hosts (and their subdomains) are fetched
+    pub allow_hosts: Vec<String>,
+    /// Hosts (and their subdomains) never fetched
+    pub deny_hosts: Vec<String>,
+    /// Media up to this size is sent inline in the request, larger media goes through the Files API
+    pub inline_max_bytes: usize,
+    /// ffmpeg used to transcode audio formats Gemini does not read, such audio is sent as is without it
+    pub ffmpeg_path: Option<PathBuf>,
+}
+
+/// MIME type for a file name or URL path by its extension
+pub fn mime_type_from_extension(path: &str) -> Option<&'static str> {
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+    let mime_type = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "mp3" | "mpga" | "mpeg" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "flac" => "audio/flac",
+        "aac" => "audio/aac",
+        "aiff" | "aif" => "audio/aiff",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "avi" => "video/x-msvideo",
+        "mpg" => "video/mpeg",
+        "wmv" => "video/x-ms-wmv",
+        "flv" => "video/x-flv",
+        "3gp" => "video/3gpp",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "xml" => "text/xml",
+        "rtf" => "text/rtf",
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+/// MIME type for the `format` of an OpenAI input_audio part
+pub fn audio_mime_type_from_format(format: &str) -> Option<&'static str> {
+    let mime_type = match format.to_ascii_lowercase().as_str() {
+        "wav" => "audio/wav",
+        "mp3" => "audio/mp3",
+        "flac" => "audio/flac",
+        "ogg" | "opus" => "audio/ogg",
+        "aac" => "audio/aac",
+        "aiff" => "audio/aiff",
+        "m4a" | "mp4" => "audio/mp4",
+        "webm" => "audio/webm",
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+// Image formats Gemini reads and PDF, by their magic bytes
+fn sniff_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [b'%', b'P', b'D', b'F', ..] => Some("application/pdf"),
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'h', b'e', b'i', b'c', ..] => Some("image/heic"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'm', b'i', b'f', b'1', ..] => Some("image/heif"),
+        _ => None,
+    }
+}
+
+// Media types remote `image_url` and `file` parts may carry
+fn is_remote_media_type(mime_type: &str, image_only: bool) -> bool {
+    if image_only {
+        return mime_type.starts_with("image/");
+    }
+    mime_type == "application/pdf" || mime_type == "text/plain" || mime_type.starts_with("audio/") || mime_type.starts_with("video/")
+}
+
+fn host_matches(host: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim_start_matches('.');
+    host == pattern || host.ends_with(&format!(".{pattern}"))
+}
+
+// Addresses that are not on the public internet: loopback, private, CGNAT, link-local, reserved and multicast.
+// IPv6 addresses embedding an IPv4 one (mapped, NAT64, 6to4) are judged by the IPv4 address.
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast()
+                || a == 0 // this network
+                || (a == 100 && (b & 0xc0) == 64) // CGNAT 100.64.0.0/10
+                || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+                || (a == 198 && (b & 0xfe) == 18) // benchmarking 198.18.0.0/15
+                || a >= 240 // reserved
+        },
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let embedded_v4 = |high: u16, low: u16| IpAddr::V4(Ipv4Addr::from(((high as u32) << 16) | low as u32));
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_internal_ip(IpAddr::V4(mapped));
+            }
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_internal_ip(embedded_v4(segments[6], segments[7]));
+            }
+            if segments[0] == 0x2002 {
+                return is_internal_ip(embedded_v4(segments[1], segments[2]));
+            }
+            ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || segments[..6] == [0; 6] // IPv4-compatible
+                || (segments[0] & 0xfe00) == 0xfc00 // unique local
+                || (segments[0] & 0xffc0) == 0xfe80 // link-local
+        },
+    }
+}
+
+// IPv4 in the forms inet_aton also accepts: 2130706433, 0x7f.1, 0177.0.0.1...
+fn parse_ipv4_loose(host: &str) -> Option<Ipv4Addr> {
+    let parts = host.split('.')
+        .map(|part| match part.strip_prefix("0x").or_else(|| part.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None if part.len() > 1 && part.starts_with('0') => u32::from_str_radix(&part[1..], 8).ok(),
+            None => part.parse().ok(),
+        })
+        .collect::<Option<Vec<u32>>>()?;
+    let (last, leading) = parts.split_last()?;
+    // The last part fills the bytes the others leave
+    if leading.len() > 3 || leading.iter().any(|part| *part > 255) || u64::from(*last) >= 1 << (8 * (4 - leading.len())) {
+        return None;
+    }
+    let address = leading.iter().enumerate().fold(*last, |address, (index, part)| address | part << (24 - 8 * index));
+    Some(Ipv4Addr::from(address))
+}
+
+// Loopback, private and link-local hosts are refused unless explicitly allowed,
+// so a request cannot make the adapter probe its own network. Names are also checked once resolved, see PublicResolver.
+fn is_internal_host(host: &str) -> bool {
+    if host == "localhost" || host.ends_with(".localhost") {
+        return true;
+    }
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => is_internal_ip(ip),
+        Err(_) => parse_ipv4_loose(host).is_some_and(|ip| is_internal_ip(IpAddr::V4(ip))),
+    }
+}
+
+// Resolves names like the default resolver, but refuses those with an internal address unless the host is allowed.
+// Checking at connect time leaves no window for the name to change between the check and the connection.
+struct PublicResolver {
+    allow_hosts: Vec<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn lookup<'a>(&'a self, host: &'a str, port: u16) -> LocalBoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+            let host = normalize_host(host);
+            let allowed = self.allow_hosts.iter().any(|pattern| host_matches(&host, pattern));
+            if !allowed && addresses.iter().any(|address| is_internal_ip(address.ip())) {
+                log::warn!("Refused to fetch media from {host}, it resolves to an internal address");
+                return Err(format!("{host} resolves to an internal address").into());
+            }
+            Ok(addresses)
+        })
+    }
+}
+
+/// Redirects followed when fetching one remote file
+const MAX_REDIRECTS: usize = 5;
+
+// Absolute URI of a Location header, which may be relative to the request URI
+fn redirect_target(base: &Uri, location: &str) -> Option<Uri> {
+    if location.contains("://") {
+        return location.parse().ok();
+    }
+    let scheme = base.scheme_str()?;
+    let authority = base.authority()?;
+    let target = if let Some(network_path) = location.strip_prefix("//") {
+        format!("{scheme}://{network_path}")
+    } else if location.starts_with('/') {
+        format!("{scheme}://{authority}{location}")
+    } else {
+        let directory = base.path().rsplit_once('/').map_or("", |(directory, _)| directory);
+        format!("{scheme}://{authority}{directory}/{location}")
+    };
+    target.parse().ok()
+}
+
+// Lowercase without the trailing dot of a fully qualified name
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Client for remote media, one per worker. Redirects are followed by fetch_remote_media so that every hop is
+/// checked, and names resolving to internal addresses are refused unless allowed.
+#[derive(Clone)]
+pub struct MediaClient(Client);
+
+impl MediaClient {
+    pub fn new(tls_config: Arc<ClientConfig>, media: &MediaConfig) -> Self {
+        let resolver = Resolver::custom(PublicResolver { allow_hosts: media.allow_hosts.clone() });
+        let connector = Connector::new()
+            .rustls_0_23(tls_config)
+            .connector(TcpConnector::new(resolver).service());
+        Self(Client::builder().connector(connector).disable_redirects().finish())
+    }
+}
+
+impl MediaConfig {
+    fn check_host(&self, host: &str) -> Result<(), Error> {
+        let host = normalize_host(host);
+        let allowed = if self.allow_hosts.is_empty() {
+            !is_internal_host(&host)
+        } else {
+            self.allow_hosts.iter().any(|pattern| host_matches(&host, pattern))
+        };
+        if !allowed || self.deny_hosts.iter().any(|pattern| host_matches(&host, pattern)) {
+            return Err(ErrorBadRequest(format!("Fetching media from {host} is not allowed")));
+        }
+        Ok(())
+    }
+
+    /// Audio Gemini can read: supported formats as is, others transcoded to FLAC when ffmpeg is configured
+    pub async fn prepare_audio(&self, mime_type: String, audio_data: Vec<u8>) -> Result<(String, Vec<u8>), Error> {
+        let mime_type_essence = mime_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if GEMINI_AUDIO_MIME_TYPES.contains(&mime_type_essence.as_str()) {
+            return Ok((mime_type, audio_data));
+        }
+        let Some(ffmpeg_path) = &self.ffmpeg_path else {
+            log::warn!("{mime_type} audio is not a format Gemini lists as supported and no ffmpeg is configured, sent as is");
+            return Ok((mime_type, audio_data));
+        };
+        log::info!("Transcoding {} bytes of {mime_type} audio to FLAC", audio_data.len());
+        // ffmpeg reads a file rather than a pipe, MP4 and M4A keep their index at the end and cannot be demuxed from a pipe.
+        // The file is only readable by this user and is removed when the guard drops, also when the client goes away mid transcode.
+        let input_file = tempfile::Builder::new().prefix("audio-").tempfile().map_err(|e| {
+            log::error!("Failed to create a temporary audio file: {e}");
+            ErrorInternalServerError("Failed to start audio transcoding")
+        })?;
+        tokio::fs::write(input_file.path(), &audio_data).await.map_err(|e| {
+            log::error!("Failed to write audio to {}: {e}", input_file.path().display());
+            ErrorInternalServerError("Failed to start audio transcoding")
+        })?;
+        let output = self.run_ffmpeg(ffmpeg_path, input_file.path()).await?;
+        drop(input_file);
+        if !output.status.success() || output.stdout.is_empty() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            log::error!("ffmpeg failed on {mime_type} audio: {stderr}");
+            return Err(ErrorBadRequest(format!("Failed to transcode {mime_type} audio: {}", stderr.trim())));
+        }
+        Ok(("audio/flac".to_string(), output.stdout))
+    }
+
+    // Gemini downsamples audio to 16 kHz mono anyway
+    async fn run_ffmpeg(&self, ffmpeg_path: &Path, input_path: &Path) -> Result<std::process::Output, Error> {
+        let ffmpeg = tokio::process::Command::new(ffmpeg_path)
+            .args(["-hide_banner", "-loglevel", "error", "-i"])
+            .arg(input_path)
+            .args(["-ac", "1", "-ar", "16000", "-f", "flac", "pipe:1"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                log::error!("Failed to start ffmpeg at {}: {e}", ffmpeg_path.display());
+                ErrorInternalServerError("Failed to start audio transcoding")
+            })?;
+        // Dropping the child on timeout kills it
+        match tokio::time::timeout(FFMPEG_TIMEOUT, ffmpeg.wait_with_output()).await {
+            Ok(output) => output.map_err(|e| ErrorInternalServerError(format!("Audio transcoding failed: {e}"))),
+            Err(_) => {
+                log::error!("ffmpeg did not finish within {}s", FFMPEG_TIMEOUT.as_secs());
+                Err(ErrorInternalServerError("Audio transcoding timed out"))
+            }
+        }
+    }
+
+    /// Download an http(s) file, returning its MIME type and bytes. With `image_only` anything but an image is refused,
+    /// otherwise anything but PDF, audio, video and plain text. The host rules apply to the URL and every redirect.
+    pub async fn fetch_remote_media(&self, client: &MediaClient, url: &str, image_only: bool) -> Result<(String, Vec<u8>), Error> {
+        let mut uri: Uri = url.parse()
+            .map_err(|_| ErrorBadRequest(format!("Invalid URL: {url}")))?;
+        let mut redirects = 0;
+        let mut response = loop {
+            if !matches!(uri.scheme_str(), Some("http" | "https")) {
+                return Err(ErrorBadRequest(format!("Invalid URL: {uri}")));
+            }
+            let host = uri.host().ok_or_else(|| ErrorBadRequest(format!("Invalid URL: {uri}")))?;
+            self.check_host(host)?;
+
+            log::info!("Fetching media: {uri}");
+            let response = client.0.get(&uri)
+                .timeout(std::time::Duration::from_secs(60))
+                .send()
+                .await
+                .map_err(|e| ErrorBadRequest(format!("Failed to fetch {uri}: {e}")))?;
+            if !response.status().is_redirection() {
+                break response;
+            }
+            if redirects == MAX_REDIRECTS {
+                return Err(ErrorBadRequest(format!("Failed to fetch {url}: too many redirects")));
+            }
+            redirects += 1;
+            uri = response.headers().get("location")
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| redirect_target(&uri, location))
+                .ok_or_else(|| ErrorBadRequest(format!("Failed to fetch {uri}: invalid redirect")))?;
+        };
+        if !response.status().is_success() {
+            return Err(ErrorBadRequest(format!("Failed to fetch {url}: {}", response.status())));
+        }
+        let bytes = response.body().limit(self.max_remote_bytes).await
+            .map_err(|e| ErrorBadRequest(format!("Failed to fetch {url}: {e}")))?;
+
+        // Servers often label media application/octet-stream, so the bytes decide first, then the header, then the path
+        let content_type = response.headers().get("content-type")
+            .and_then(|h| h.to_str().ok())
+            .map(|c| c.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+            .filter(|c| !c.is_empty() && c != "application/octet-stream");
+        let mime_type = sniff_mime_type(&bytes)
+            .map(String::from)
+            .or(content_type)
+            .or_else(|| mime_type_from_extension(uri.path()).map(String::from))
+            .filter(|mime_type| is_remote_media_type(mime_type, image_only))
+            .ok_or_else(|| ErrorBadRequest(format!("{url} is not {}", if image_only { "an image" } else { "a PDF, audio, video or text file" })))?;
+        Ok((mime_type, bytes.to_vec()))
+    }
+}
+
+/// Time of an RFC 3339 UTC timestamp such as the `expirationTime` of a Files API file, fractions of a second dropped
+pub fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let date: Vec<i64> = date.split('-').map(str::parse).collect::<Result<_, _>>().ok()?;
+    let time: Vec<u64> = time.split('.').next()?.split(':').map(str::parse).collect::<Result<_, _>>().ok()?;
+    let (&[year, month, day], &[hour, minute, second]) = (date.as_slice(), time.as_slice()) else {
+        return None;
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + hour * 3600 + minute * 60 + second))
+}
+
+/// Gemini deletes uploaded files 48 hours after the upload, assumed when it gives no expiration time
+const FILE_TTL: Duration = Duration::from_secs(48 * 3600);
+
+/// Cached files this close to their deletion are uploaded again, so a request never references a file deleted mid-generation
+const FILE_REFRESH_MARGIN: Duration = Duration::from_secs(3600);
+
+struct CachedUpload {
+    file_uri: String,
+    expires_at: Instant,
+}
+
+/// Files API URIs of uploaded media by API key and SHA-256 of the bytes.
+/// Clients resend the whole conversation every turn, this keeps its media from being uploaded again each time.
+#[derive(Default)]
+pub struct UploadCache {
+    entries: Mutex<HashMap<(String, [u8; 32]), CachedUpload>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl UploadCache {
+    /// Cache key of media uploaded with an API key, files belong to the project of the key
+    pub fn key(api_key: &str, data: &[u8]) -> (String, [u8; 32]) {
+        (api_key.to_string(), Sha256::digest(data).into())
+    }
+
+    /// File URI of an earlier upload that is not about to expire
+    pub fn get(&self, key: &(String, [u8; 32])) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let file_uri = match entries.get(key) {
+            Some(cached) if cached.expires_at > Instant::now() + FILE_REFRESH_MARGIN => Some(cached.file_uri.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            },
+            None => None,
+        };
+        let counter = if file_uri.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        log::info!(
+            "Upload cache {}, hits: {}, misses: {}, entries: {}",
+            if file_uri.is_some() { "hit" } else { "miss" },
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            entries.len()
+        );
+        file_uri
+    }
+
+    /// Record a file uploaded just now with the expiration time Gemini gave, dropping entries that are due for refresh anyway
+    pub fn insert(&self, key: (String, [u8; 32]), file_uri: String, expiration_time: Option<SystemTime>) {
+        let now = Instant::now();
+        let expires_at = match expiration_time.map(|expiration_time| expiration_time.duration_since(SystemTime::now())) {
+            Some(Ok(time_left)) => now + time_left,
+            Some(Err(_)) => now,
+            None => now + FILE_TTL,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, cached| cached.expires_at > now + FILE_REFRESH_MARGIN);
+        entries.insert(key, CachedUpload { file_uri, expires_at });
+    }
+
+    /// Drop the files of an API key that an upstream error names. Gemini can delete files early,
+    /// and a key of another project cannot read them, so they would fail every later turn.
+    pub fn forget_named_in(&self, api_key: &str, error: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(key_api_key, _), cached| {
+            let file_name = cached.file_uri.rsplit('/').next().unwrap_or(&cached.file_uri);
+            let named = key_api_key == api_key && !file_name.is_empty() && error.contains(file_name);
+            if named {
+                log::info!("Dropped {} from the upload cache, Gemini refused it", cached.file_uri);
+            }
+            !named
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), at(0));
+        assert_eq!(parse_timestamp("2000-02-29T12:30:15Z"), at(951_827_415));
+        assert_eq!(parse_timestamp("2026-10-19T02:47:30.123456Z"), at(1_792_378_050));
+        for timestamp in ["2026-10-19T02:47:30", "2026-13-01T00:00:00Z", "2026-10-19 02:47:30Z", "soon"] {
+            assert_eq!(parse_timestamp(timestamp), None, "{timestamp}");
+        }
+    }
+
+    #[test]
+    fn forgets_files_named_in_errors() {
+        let cache = UploadCache::default();
+        let expires = Some(SystemTime::now() + FILE_TTL);
+        cache.insert(UploadCache::key("key", b"a"), format!("{GOOGLE_FILES_URI_PREFIX}abc123"), expires);
+        cache.insert(UploadCache::key("key", b"b"), format!("{GOOGLE_FILES_URI_PREFIX}def456"), expires);
+        cache.insert(UploadCache::key("other", b"a"), format!("{GOOGLE_FILES_URI_PREFIX}abc123"), expires);
+        cache.forget_named_in("key", "You do not have permission to access the File abc123 or it may not exist.");
+        assert_eq!(cache.get(&UploadCache::key("key", b"a")), None);
+        assert!(cache.get(&UploadCache::key("key", b"b")).is_some());
+        assert!(cache.get(&UploadCache::key("other", b"a")).is_some());
+    }
+
+    #[test]
+    fn limits_remote_media_types() {
+        for mime_type in ["application/pdf", "text/plain", "audio/mpeg", "video/mp4"] {
+            assert!(is_remote_media_type(mime_type, false), "{mime_type} should be accepted");
+            assert!(!is_remote_media_type(mime_type, true), "{mime_type} is not an image");
+        }
+        for mime_type in ["text/html", "application/json", "application/x-sh", "image/png"] {
+            assert!(!is_remote_media_type(mime_type, false), "{mime_type} should be refused");
+        }
+        assert!(is_remote_media_type("image/png", true));
+    }
+
+    #[test]
+    fn refuses_internal_hosts() {
+        for host in [
+            "localhost", "localhost.", "api.localhost", "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+            "100.64.0.1", "100.127.255.255", "0.0.0.0", "[::1]", "[::ffff:127.0.0.1]", "[::ffff:7f00:1]", "[fd00::1]", "[fe80::1]",
+            "[64:ff9b::a00:1]", "[2002:c0a8:101::]", "2130706433", "0x7f000001", "0177.0.0.1", "127.1", "10.0x10203",
+        ] {
+            assert!(is_internal_host(&normalize_host(host)), "{host} should be internal");
+        }
+        for host in ["example.com", "8.8.8.8", "100.128.0.1", "[2001:4860:4860::8888]", "[::ffff:8.8.8.8]", "134744072", "1.2.3.example"] {
+            assert!(!is_internal_host(&normalize_host(host)), "{host} should be public");
+        }
+    }
+
+    #[test]
+    fn parses_inet_aton_forms() {
+        assert_eq!(parse_ipv4_loose("2130706433"), Some(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(parse_ipv4_loose("0x7f.0.0.1"), Some(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(parse_ipv4_loose("10.258"), Some(Ipv4Addr::new(10, 0, 1, 2)));
+        assert_eq!(parse_ipv4_loose("1.2.3.256"), None);
+        assert_eq!(parse_ipv4_loose("4294967296"), None);
+        assert_eq!(parse_ipv4_loose("1.2.3.4.5"), None);
+        assert_eq!(parse_ipv4_loose("example.com"), None);
+    }
+
+    #[test]
+    fn resolves_redirect_locations() {
+        let base: Uri = "https://example.com/media/cat.png?size=1".parse().unwrap();
+        let target = |location| redirect_target(&base, location).map(|uri| uri.to_string());
+        assert_eq!(target("http://127.0.0.1/x").as_deref(), Some("http://127.0.0.1/x"));
+        assert_eq!(target("//cdn.example.com/a.png").as_deref(), Some("https://cdn.example.com/a.png"));
+        assert_eq!(target("/b.png").as_deref(), Some("https://example.com/b.png"));
+        assert_eq!(target("c.png").as_deref(), Some("https://example.com/media/c.png"));
+    }
+}
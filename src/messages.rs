@@ -0,0 +1,372 @@
+use crate::app_state::AppState;
+use crate::blocks::{Block, BlockAccumulator, BlockEvent, TextKind};
+use crate::proxy::{
+    build_google_request, receive_google_response, relay_google_stream, response_builder_from, rewrap_upstream_error, send_google_request, unix_timestamp, ChunkEncoder,
+};
+use crate::transformers::StreamState;
+use actix_web::{error::ErrorBadRequest, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use awc::Client;
+use serde_json::{json, Value};
+
+fn blocks_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks.iter().filter_map(|b| b.get("text").and_then(Value::as_str)).collect::<Vec<&str>>().join("\n"),
+        _ => String::new(),
+    }
+}
+
+// Anthropic media source (base64 or url) as an OpenAI style url
+fn source_to_url(source: &Value) -> Option<String> {
+    match source.get("type").and_then(Value::as_str) {
+        Some("base64") => Some(format!(
+            "data:{};base64,{}",
+            source.get("media_type").and_then(Value::as_str).unwrap_or("application/octet-stream"),
+            source.get("data").and_then(Value::as_str).unwrap_or("")
+        )),
+        Some("url") => source.get("url").and_then(Value::as_str).map(|url| url.to_string()),
+        _ => None,
+    }
+}
+
+// Convert Anthropic messages into chat messages, tool_result blocks become tool messages
+fn transform_anthropic_messages_to_chat(messages: &[Value]) -> Result<Vec<Value>, Error> {
+    let mut chat_messages = Vec::new();
+    for msg in messages {
+        let role = msg.get("role").and_then(Value::as_str).unwrap_or("user");
+        let blocks = match msg.get("content") {
+            Some(Value::String(text)) => {
+                chat_messages.push(json!({ "role": role, "content": text }));
+                continue;
+            },
+            Some(Value::Array(blocks)) => blocks,
+            _ => return Err(ErrorBadRequest("message content must be a string or an array of content blocks")),
+        };
+
+        let mut parts = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in blocks {
+            match block.get("type").and_then(Value::as_str) {
+                Some("text") => parts.push(json!({ "type": "text", "text": block["text"] })),
+                Some("image") => {
+                    let url = source_to_url(&block["source"]).ok_or_else(|| ErrorBadRequest("Unsupported image source"))?;
+                    parts.push(json!({ "type": "image_url", "image_url": { "url": url } }));
+                },
+                Some("document") => match block["source"].get("type").and_then(Value::as_str) {
+                    Some("text") => parts.push(json!({ "type": "text", "text": block["source"]["data"] })),
+                    _ => {
+                        let url = source_to_url(&block["source"]).ok_or_else(|| ErrorBadRequest("Unsupported document source"))?;
+                        parts.push(json!({ "type": "file", "file": { "file_data": url, "filename": block.get("title").cloned().unwrap_or(Value::Null) } }));
+                    }
+                },
+                Some("tool_use") => tool_calls.push(json!({
+                    "id": block["id"],
+                    "type": "function",
+                    "function": { "name": block["name"], "arguments": block.get("input").cloned().unwrap_or(json!({})).to_string() }
+                })),
+                // Results answer the previous assistant turn, so they go before this turn's own content
+                Some("tool_result") => {
+                    let mut output = blocks_text(block.get("content").unwrap_or(&Value::Null));
+                    if block.get("is_error").and_then(Value::as_bool) == Some(true) {
+                        output = format!("Error: {output}");
+                    }
+                    chat_messages.push(json!({ "role": "tool", "tool_call_id": block["tool_use_id"], "content": output }));
+                },
+                Some("thinking") | Some("redacted_thinking") => log::debug!("Thinking block in request skipped"),
+                other => log::warn!("Unknown Anthropic content block type: {other:?}"),
+            }
+        }
+        if !parts.is_empty() || !tool_calls.is_empty() {
+            let mut chat_message = json!({ "role": role, "content": parts });
+            if !tool_calls.is_empty() {
+                chat_message["tool_calls"] = json!(tool_calls);
+            }
+            chat_messages.push(chat_message);
+        }
+    }
+    Ok(chat_messages)
+}
+
+fn transform_anthropic_tool_choice_to_chat(tool_choice: &Value) -> Value {
+    match tool_choice.get("type").and_then(Value::as_str) {
+        Some("any") => json!("required"),
+        Some("tool") => json!({ "type": "function", "function": { "name": tool_choice["name"] } }),
+        Some("none") => json!("none"),
+        _ => json!("auto"),
+    }
+}
+
+// Build the chat completions body for a Messages request
+fn anthropic_to_chat_request(json_body: &Value) -> Result<(Value, bool), Error> {
+    let empty = vec![];
+    let mut messages = Vec::new();
+    if let Some(system) = json_body.get("system") {
+        messages.push(json!({ "role": "system", "content": blocks_text(system) }));
+    }
+    messages.extend(transform_anthropic_messages_to_chat(json_body.get("messages").and_then(Value::as_array).unwrap_or(&empty))?);
+
+    let mut chat_body = json!({
+        "model": json_body["model"],
+        "messages": messages,
+        "stream": json_body.get("stream").cloned().unwrap_or(json!(false)),
+        // message_delta carries the output tokens
+        "stream_options": { "include_usage": true },
+        "thought_mode": "reasoning_content"
+    });
+    for (from, to) in [("max_tokens", "max_tokens"), ("stop_sequences", "stop"), ("temperature", "temperature"), ("top_p", "top_p")] {
+        if let Some(value) = json_body.get(from) {
+            chat_body[to] = value.clone();
+        }
+    }
+    let thinking = json_body.get("thinking");
+    let thinking_enabled = thinking.and_then(|t| t.get("type")).and_then(Value::as_str) == Some("enabled");
+    // Anthropic models do not think unless asked, Gemini 2.5 thinks by default
+    if thinking_enabled {
+        if let Some(budget) = thinking.and_then(|t| t.get("budget_tokens")) {
+            chat_body["thinking_budget"] = budget.clone();
+        }
+    } else {
+        chat_body["reasoning_effort"] = json!("none");
+    }
+    if let Some(tools) = json_body.get("tools").and_then(Value::as_array) {
+        let chat_tools: Vec<Value> = tools.iter()
+            .filter(|tool| tool.get("input_schema").is_some())
+            .map(|tool| {
+                let mut function = json!({ "name": tool["name"], "parameters": tool["input_schema"] });
+                if let Some(description) = tool.get("description") {
+                    function["description"] = description.clone();
+                }
+                json!({ "type": "function", "function": function })
+            })
+            .collect();
+        chat_body["tools"] = json!(chat_tools);
+    }
+    if let Some(tool_choice) = json_body.get("tool_choice") {
+        chat_body["tool_choice"] = transform_anthropic_tool_choice_to_chat(tool_choice);
+    }
+    Ok((chat_body, thinking_enabled))
+}
+
+fn map_stop_reason(finish_reason: Option<&str>) -> &'static str {
+    match finish_reason {
+        Some("length") => "max_tokens",
+        Some("tool_calls") => "tool_use",
+        Some("content_filter") => "refusal",
+        _ => "end_turn",
+    }
+}
+
+fn transform_chat_usage_to_anthropic(usage: Option<&Value>) -> Value {
+    let count = |value: &Value| value.as_i64().unwrap_or(0);
+    let Some(usage) = usage else {
+        return json!({ "input_tokens": 0, "output_tokens": 0 });
+    };
+    let cached_tokens = count(&usage["prompt_tokens_details"]["cached_tokens"]);
+    json!({
+        "input_tokens": count(&usage["prompt_tokens"]) - cached_tokens,
+        "cache_read_input_tokens": cached_tokens,
+        "output_tokens": count(&usage["completion_tokens"])
+    })
+}
+
+// Anthropic error type for an HTTP status
+fn anthropic_error_type(status: u16) -> &'static str {
+    match status {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        503 | 529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+fn anthropic_error(status: u16, message: Value) -> Value {
+    json!({ "type": "error", "error": { "type": anthropic_error_type(status), "message": message } })
+}
+
+// Anthropic content block for a finished block
+fn anthropic_content_block(block: &Block) -> Value {
+    match block {
+        Block::Text { kind: TextKind::Reasoning, text } => json!({ "type": "thinking", "thinking": text, "signature": "" }),
+        Block::Text { kind: TextKind::Text, text } => json!({ "type": "text", "text": text }),
+        Block::ToolCall { id, name, arguments } => json!({
+            "type": "tool_use", "id": id, "name": name, "input": serde_json::from_str::<Value>(arguments).unwrap_or(json!({}))
+        }),
+    }
+}
+
+/// Builds an Anthropic message from chat deltas, emitting the Messages streaming events on the way
+struct MessagesEncoder {
+    message: Value,
+    blocks: BlockAccumulator,
+    started: bool,
+    usage: Option<Value>,
+    /// Whether an error event ended the stream
+    failed: bool,
+}
+
+impl MessagesEncoder {
+    fn event(event_type: &str, mut payload: Value) -> String {
+        payload["type"] = json!(event_type);
+        format!("event: {event_type}\ndata: {payload}\n\n")
+    }
+
+    fn start(&mut self) -> String {
+        if self.started {
+            return String::new();
+        }
+        self.started = true;
+        Self::event("message_start", json!({ "message": self.message }))
+    }
+
+    fn block_events(&self, events: Vec<BlockEvent>) -> String {
+        events.into_iter().map(|event| match event {
+            BlockEvent::Opened(index) => {
+                let content_block = match &self.blocks.blocks[index] {
+                    Block::Text { kind: TextKind::Reasoning, .. } => json!({ "type": "thinking", "thinking": "", "signature": "" }),
+                    _ => json!({ "type": "text", "text": "" }),
+                };
+                Self::event("content_block_start", json!({ "index": index, "content_block": content_block }))
+            },
+            BlockEvent::Delta(index, text) => {
+                let delta = match &self.blocks.blocks[index] {
+                    Block::Text { kind: TextKind::Reasoning, .. } => json!({ "type": "thinking_delta", "thinking": text }),
+                    _ => json!({ "type": "text_delta", "text": text }),
+                };
+                Self::event("content_block_delta", json!({ "index": index, "delta": delta }))
+            },
+            BlockEvent::Closed(index) => Self::event("content_block_stop", json!({ "index": index })),
+            // The input arrives as one input_json_delta
+            BlockEvent::ToolCall(index) => {
+                let Block::ToolCall { id, name, arguments } = &self.blocks.blocks[index] else {
+                    return String::new();
+                };
+                let content_block = json!({ "type": "tool_use", "id": id, "name": name, "input": {} });
+                let mut events = Self::event("content_block_start", json!({ "index": index, "content_block": content_block }));
+                events.push_str(&Self::event("content_block_delta", json!({ "index": index, "delta": { "type": "input_json_delta", "partial_json": arguments } })));
+                events.push_str(&Self::event("content_block_stop", json!({ "index": index })));
+                events
+            },
+        }).collect()
+    }
+
+    fn push_chat_choice(&mut self, choice: &Value, message_key: &str) -> String {
+        let events = self.blocks.push_chat_choice(choice, message_key);
+        self.block_events(events)
+    }
+
+    fn final_message(&self) -> Value {
+        let mut message = self.message.clone();
+        message["content"] = self.blocks.blocks.iter().map(anthropic_content_block).collect();
+        message["stop_reason"] = json!(map_stop_reason(self.blocks.finish_reason.as_deref()));
+        message["usage"] = transform_chat_usage_to_anthropic(self.usage.as_ref());
+        message
+    }
+}
+
+impl ChunkEncoder for MessagesEncoder {
+    fn encode(&mut self, chunk: Value) -> String {
+        let mut events = self.start();
+        if let Some(error) = chunk.get("error") {
+            let status = error.get("code").and_then(Value::as_u64).unwrap_or(500) as u16;
+            events.push_str(&Self::event("error", anthropic_error(status, error.get("message").cloned().unwrap_or(Value::Null))));
+            self.failed = true;
+            return events;
+        }
+        if let Some(usage) = chunk.get("usage") {
+            self.usage = Some(usage.clone());
+        }
+        if let Some(choice) = chunk.get("choices").and_then(Value::as_array).and_then(|choices| choices.first()) {
+            events.push_str(&self.push_chat_choice(choice, "delta"));
+        }
+        events
+    }
+
+    fn finish(&mut self) -> String {
+        // Nothing follows an error event
+        if self.failed {
+            return String::new();
+        }
+        let mut events = self.start();
+        let closed = self.blocks.close();
+        events.push_str(&self.block_events(closed));
+        let final_message = self.final_message();
+        events.push_str(&Self::event("message_delta", json!({
+            "delta": { "stop_reason": final_message["stop_reason"], "stop_sequence": null },
+            // message_start goes out before upstream reports usage, so the input and cache tokens come here too
+            "usage": final_message["usage"]
+        })));
+        events.push_str(&Self::event("message_stop", json!({})));
+        events
+    }
+}
+
+/// POST /v1/messages
+pub async fn messages(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> HttpResponse {
+    // Errors of the adapter itself get the same error body as upstream ones
+    handle_messages(req, body_data, data, client).await.unwrap_or_else(|e| {
+        let status = e.as_response_error().status_code();
+        HttpResponse::build(status).json(anthropic_error(status.as_u16(), json!(e.to_string())))
+    })
+}
+
+async fn handle_messages(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    log::info!("Got messages request: {}", String::from_utf8_lossy(&body_data));
+
+    let json_body: Value = serde_json::from_slice(&body_data)
+        .map_err(|_| ErrorBadRequest("Failed to parse JSON body"))?;
+    let (chat_body, thinking_enabled) = anthropic_to_chat_request(&json_body)?;
+    let google_request = build_google_request(&req, &chat_body, &data, &client).await?;
+    let upstream_response = send_google_request(&google_request, &data, &client).await?;
+
+    let message_id = format!("msg_{}", uuid::Uuid::new_v4().simple());
+    let mut encoder = MessagesEncoder {
+        message: json!({
+            "id": message_id,
+            "type": "message",
+            "role": "assistant",
+            "model": json_body["model"],
+            "content": [],
+            "stop_reason": null,
+            "stop_sequence": null,
+            "usage": { "input_tokens": 0, "output_tokens": 0 }
+        }),
+        blocks: BlockAccumulator::new(!thinking_enabled),
+        started: false,
+        usage: None,
+        failed: false,
+    };
+    let mut state = StreamState::new(message_id, unix_timestamp(), data.thought_signatures.clone());
+    state.include_usage = true;
+
+    if upstream_response.content_type().contains("text/event-stream") {
+        let mut response = response_builder_from(&upstream_response);
+        Ok(response.streaming(relay_google_stream(upstream_response, &google_request, state, encoder)))
+    } else {
+        match receive_google_response(upstream_response, &google_request, &data.upload_cache, &mut state).await? {
+            Ok(chat_response) => {
+                if let Some(choice) = chat_response.get("choices").and_then(Value::as_array).and_then(|choices| choices.first()) {
+                    encoder.push_chat_choice(choice, "message");
+                }
+                encoder.usage = chat_response.get("usage").cloned();
+                let message = encoder.final_message();
+                log::info!("Replied to client: {message}");
+                Ok(HttpResponse::Ok().json(message))
+            },
+            Err(upstream_error) => Ok(rewrap_upstream_error(upstream_error, |status, message| anthropic_error(status.as_u16(), json!(message))).await),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::app_state::AppState;
+use crate::proxy::{NO_THOUGHT_PROCESS_SUFFIX, THINKING_ENABLED_MODELS};
+use crate::utils::{extract_api_key, send_google_json};
+use actix_web::{error::ErrorBadRequest, web, Error, HttpRequest, HttpResponse};
+use awc::Client;
+use serde_json::{json, Value};
+
+fn transform_google_model_to_openai(id: &str, model: &Value) -> Value {
+    json!({
+        "id": id,
+        "object": "model",
+        "created": 0,
+        "owned_by": "google",
+        "display_name": model.get("displayName").cloned().unwrap_or(Value::Null)
+    })
+}
+
+// The model itself plus the adapter's synthetic variants of it
+fn transform_google_model_to_openai_variants(model: &Value) -> Vec<Value> {
+    let Some(id) = model.get("name").and_then(Value::as_str).map(|name| name.trim_start_matches("models/")) else {
+        return Vec::new();
+    };
+    let mut variants = vec![transform_google_model_to_openai(id, model)];
+    let can_think = model.get("thinking").and_then(Value::as_bool) == Some(true)
+        || THINKING_ENABLED_MODELS.iter().any(|thinking_enabled_model| id.contains(thinking_enabled_model));
+    let can_generate = model.get("supportedGenerationMethods").and_then(Value::as_array)
+        .is_some_and(|methods| methods.iter().any(|m| m.as_str() == Some("generateContent")));
+    if can_think && can_generate {
+        variants.push(transform_google_model_to_openai(&format!("{id}{NO_THOUGHT_PROCESS_SUFFIX}"), model));
+    }
+    variants
+}
+
+/// GET /v1/models
+pub async fn list_models(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    let api_key = extract_api_key(&req)
+        .ok_or_else(|| ErrorBadRequest("No API key provided"))?;
+
+    let mut models = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut google_url = format!("{}/models?pageSize=1000&key={api_key}", data.upstream_url);
+        if let Some(page_token) = &page_token {
+            google_url.push_str(&format!("&pageToken={page_token}"));
+        }
+        let page = match send_google_json(client.get(&google_url), None).await? {
+            Ok(page) => page,
+            Err(upstream_error) => return Ok(upstream_error),
+        };
+        if let Some(google_models) = page.get("models").and_then(Value::as_array) {
+            models.extend(google_models.iter().flat_map(transform_google_model_to_openai_variants));
+        }
+        page_token = page.get("nextPageToken").and_then(Value::as_str)
+            .filter(|token| !token.is_empty())
+            .map(|token| token.to_string());
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "object": "list",
+        "data": models
+    })))
+}
+
+/// GET /v1/models/{model}
+pub async fn get_model(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    let api_key = extract_api_key(&req)
+        .ok_or_else(|| ErrorBadRequest("No API key provided"))?;
+
+    let model_id = path.into_inner();
+    let model_name = model_id.trim_end_matches(NO_THOUGHT_PROCESS_SUFFIX);
+    let google_url = format!("{}/models/{model_name}?key={api_key}", data.upstream_url);
+    match send_google_json(client.get(&google_url), None).await? {
+        Ok(model) => Ok(HttpResponse::Ok().json(transform_google_model_to_openai(&model_id, &model))),
+        Err(upstream_error) => Ok(upstream_error),
+    }
+}
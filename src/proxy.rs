@@ -1,50 +1,109 @@
-use crate::app_state::AppState;
-use crate::transformers::{transform_google_stream_to_openai, transform_google_to_openai, transform_openai_to_google};
-use crate::utils::extract_api_key;
-use actix_web::{error::{ErrorInternalServerError, ErrorBadRequest}, web, Error, HttpMessage, HttpRequest, HttpResponse};
-use awc::Client;
-use futures_util::stream::{StreamExt, TryStreamExt};
-use serde_json::Value;
-
-/// Thinking will be enabled by name if matched by thinking_enabled_models
+use crate::app_state::{AppState, ImageOutput, ThoughtMode};
+use crate::audio::speak_chat_completion;
+use crate::media::{MediaClient, UploadCache};
+use crate::transformers::{
+    finish_google_stream_to_openai, new_chat_completion_id, openai_stream_error, transform_google_stream_to_openai, transform_google_to_openai, transform_openai_to_google, StreamState,
+};
+use crate::sse::SseDecoder;
+use crate::utils::{extract_api_key, GOOGLE_JSON_BODY_LIMIT};
+use actix_web::{dev::{Decompress, Payload}, error::{ErrorInternalServerError, ErrorBadRequest}, http::StatusCode, web::{self, Bytes}, Error, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use awc::{Client, ClientResponse};
+use futures_util::stream::{Stream, StreamExt};
+use serde_json::{json, Value};
+
+/// Model name suffix asking not to relay the thought process
+pub const NO_THOUGHT_PROCESS_SUFFIX: &str = "-no-thought-process";
+
+/// Model names containing one of these have thinking enabled
+pub const THINKING_ENABLED_MODELS: [&str; 2] = ["gemini-2.0-flash-thinking", "gemini-2.5"];
+
+/// Thinking will be enabled by name if matched by THINKING_ENABLED_MODELS
 pub struct ThinkingConfig {
     pub enabled: bool,
     pub budget: Option<i64>,
 }
 
-pub async fn reverse_proxy(
-    req: HttpRequest,
-    body_data: web::Bytes,
-    data: web::Data<AppState>,
-    client: web::Data<Client>,
-) -> Result<HttpResponse, Error> {
-    if !req.path().starts_with("/v1/chat/completions") {
-        return Ok(HttpResponse::NotFound().body("Not Found"));
-    }
+/// `modalities: ["audio"]` of a chat request, the reply is spoken afterwards by a TTS model
+pub struct AudioOutput {
+    /// wav or pcm16
+    pub format: String,
+    pub voice: String,
+}
 
-    log::info!("Got request: {}", String::from_utf8_lossy(&body_data));
+/// An OpenAI style request translated for Gemini, with what is needed to translate the reply back
+pub struct GoogleRequest {
+    pub api_key: String,
+    /// Upstream model name, without the adapter's suffixes
+    pub model_name: String,
+    pub no_thought_process: bool,
+    pub thought_mode: ThoughtMode,
+    pub image_output: ImageOutput,
+    pub is_stream: bool,
+    pub include_usage: bool,
+    pub audio_output: Option<AudioOutput>,
+    pub body: Value,
+}
 
-    let json_body: Value = serde_json::from_slice(&body_data)
-        .map_err(|_| ErrorInternalServerError("Failed to parse JSON body"))?;
+pub type UpstreamResponse = ClientResponse<Decompress<Payload>>;
 
+/// Build the Gemini generateContent request for an OpenAI chat completions style body
+pub async fn build_google_request(
+    req: &HttpRequest,
+    json_body: &Value,
+    data: &AppState,
+    client: &Client,
+) -> Result<GoogleRequest, Error> {
     let is_stream = json_body.get("stream").and_then(|v| v.as_bool()).unwrap_or(false);
-    let api_key = extract_api_key(&req)
+    let include_usage = json_body.get("stream_options")
+        .and_then(|o| o.get("include_usage"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let api_key = extract_api_key(req)
         .ok_or_else(|| ErrorBadRequest("No API key provided"))?;
+    let wants_audio = json_body.get("modalities")
+        .and_then(|m| m.as_array())
+        .is_some_and(|modalities| modalities.iter().any(|m| m.as_str() == Some("audio")));
+    let audio_output = if wants_audio {
+        // Gemini TTS outputs PCM, only containers that need no encoder are available
+        let audio = json_body.get("audio");
+        let format = audio.and_then(|a| a.get("format")).and_then(|f| f.as_str()).unwrap_or("wav");
+        match (format, is_stream) {
+            ("pcm16", _) | ("wav", false) => {},
+            ("wav", true) => return Err(ErrorBadRequest("Streaming audio output supports the pcm16 format only")),
+            _ => return Err(ErrorBadRequest(format!("Unsupported audio format: {format}, only wav and pcm16 are available"))),
+        }
+        let voice = audio.and_then(|a| a.get("voice")).and_then(|v| v.as_str()).unwrap_or("alloy");
+        Some(AudioOutput { format: format.to_string(), voice: voice.to_string() })
+    } else {
+        None
+    };
 
     let model_name_in_request = json_body["model"].as_str()
         .ok_or_else(|| ErrorBadRequest("Model not found in request"))?;
-    let no_thought_process = model_name_in_request.ends_with("-no-thought-process");
+    let no_thought_process = model_name_in_request.ends_with(NO_THOUGHT_PROCESS_SUFFIX);
     let model_name = if no_thought_process {
-        model_name_in_request.trim_end_matches("-no-thought-process")
+        model_name_in_request.trim_end_matches(NO_THOUGHT_PROCESS_SUFFIX)
     } else {
         model_name_in_request
     };
 
-    let thinking_enabled_models = ["gemini-2.0-flash-thinking", "gemini-2.5"];
+    let thought_mode = match json_body.get("thought_mode").and_then(|v| v.as_str()) {
+        // The spoken reply must not contain the thought process
+        _ if audio_output.is_some() => ThoughtMode::ReasoningContent,
+        Some(mode) => ThoughtMode::from_request(mode)
+            .ok_or_else(|| ErrorBadRequest(format!("Unknown thought_mode: {mode}")))?,
+        None => data.thought_mode,
+    };
+    let image_output = match json_body.get("image_output").and_then(|v| v.as_str()) {
+        Some(output) => ImageOutput::from_request(output)
+            .ok_or_else(|| ErrorBadRequest(format!("Unknown image_output: {output}")))?,
+        None => data.image_output,
+    };
+
     let mut must_think_models = std::collections::HashMap::new();
     must_think_models.insert("gemini-2.5-pro", 128); // 128 is minimal settable
-    let thinking_enabled = thinking_enabled_models.iter().any(|thinking_enabled_model| model_name_in_request.contains(thinking_enabled_model));
-    let thinking_budget_in_request: Option<i64> = json_body
+    let thinking_enabled = THINKING_ENABLED_MODELS.iter().any(|thinking_enabled_model| model_name_in_request.contains(thinking_enabled_model));
+    let reasoning_effort_budget: Option<i64> = json_body
         .get("reasoning_effort")
         .and_then(|v| v.as_str())
         .map(|s| match s {
@@ -65,21 +124,42 @@ pub async fn reverse_proxy(
             },
             _ => 1024, // Value set but not supported mode, assuming a low effort
         });
+    // An explicit thinking_budget takes precedence over reasoning_effort
+    let thinking_budget_in_request = json_body.get("thinking_budget").and_then(|v| v.as_i64()).or(reasoning_effort_budget);
     let thinking_config = ThinkingConfig {
         enabled: thinking_enabled,
         budget: thinking_budget_in_request,
     };
 
     // Transform the OpenAI request to Google's format
-    let google_body = transform_openai_to_google(&json_body, &client, &api_key, &thinking_config).await;
+    let media_client = req.app_data::<web::Data<MediaClient>>()
+        .ok_or_else(|| ErrorInternalServerError("No media client configured"))?;
+    let google_body = transform_openai_to_google(json_body, client, media_client, &api_key, &thinking_config, data).await?;
+
+    Ok(GoogleRequest {
+        api_key,
+        model_name: model_name.to_string(),
+        no_thought_process,
+        thought_mode,
+        image_output,
+        is_stream,
+        include_usage,
+        audio_output,
+        body: google_body,
+    })
+}
 
-    let google_body_str = serde_json::to_string(&google_body)
+/// Send a generateContent (or streamGenerateContent) request upstream
+pub async fn send_google_request(google_request: &GoogleRequest, data: &AppState, client: &Client) -> Result<UpstreamResponse, Error> {
+    let google_body_str = serde_json::to_string(&google_request.body)
         .map_err(|_| ErrorInternalServerError("Failed to serialize Google body"))?;
 
     log::info!("Converted request: {google_body_str}");
 
-    let google_base_url = data.upstream_url.clone();
-    let google_url = if is_stream {
+    let google_base_url = &data.upstream_url;
+    let model_name = &google_request.model_name;
+    let api_key = &google_request.api_key;
+    let google_url = if google_request.is_stream {
         format!("{google_base_url}/models/{model_name}:streamGenerateContent?alt=sse&key={api_key}")
     } else {
         format!("{google_base_url}/models/{model_name}:generateContent?key={api_key}")
@@ -90,55 +170,221 @@ pub async fn reverse_proxy(
 
     log::info!("Forwarding request to: {google_url}");
 
-    match forward_req.timeout(std::time::Duration::from_secs(600)).send_body(google_body_str).await {
-        Ok(mut upstream_response) => {
-            let status: awc::http::StatusCode = upstream_response.status();
-            let mut response = HttpResponse::build(status);
+    forward_req.timeout(std::time::Duration::from_secs(600)).send_body(google_body_str).await
+        .map_err(|err| {
+            log::error!("Failed to forward request: {err:?}");
+            ErrorInternalServerError("Failed to connect to upstream server.")
+        })
+}
 
-            for (name, value) in upstream_response.headers().iter() {
-                if name.as_str().contains("content-encoding") {
-                    log::debug!("Not copied: content-encoding = {}", value.to_str().unwrap_or("PARSE HEADER VALUE ERROR"));
-                } else {
-                    response.insert_header((name.clone(), value.clone()));
-                }
-            }
+/// Response builder carrying the upstream status and headers
+pub fn response_builder_from(upstream_response: &UpstreamResponse) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(upstream_response.status());
+    for (name, value) in upstream_response.headers().iter() {
+        if name.as_str().contains("content-encoding") || name.as_str().contains("content-length") {
+            log::debug!("Not copied: {name} = {}", value.to_str().unwrap_or("PARSE HEADER VALUE ERROR"));
+        } else {
+            response.insert_header((name.clone(), value.clone()));
+        }
+    }
+    response
+}
+
+/// Read a non-stream reply and translate it to a chat.completion.
+/// An unsuccessful upstream reply is returned as `Ok(Err(response))` so it can be relayed as is.
+pub async fn receive_google_response(
+    mut upstream_response: UpstreamResponse,
+    google_request: &GoogleRequest,
+    upload_cache: &UploadCache,
+    state: &mut StreamState,
+) -> Result<Result<Value, HttpResponse>, Error> {
+    let body = upstream_response.body().limit(GOOGLE_JSON_BODY_LIMIT).await?;
+    log::info!("Got reply from Google: {}", String::from_utf8_lossy(&body));
+    if !upstream_response.status().is_success() {
+        upload_cache.forget_named_in(&google_request.api_key, &String::from_utf8_lossy(&body));
+        return Ok(Err(response_builder_from(&upstream_response).body(body)));
+    }
+
+    let google_response: Value = serde_json::from_slice(&body)
+        .map_err(|_| ErrorInternalServerError("Failed to parse Google response"))?;
 
-            if upstream_response.content_type().contains("text/event-stream") {
-                let mut prev_is_thought = false;
-                let up_stream = upstream_response.into_stream()
-                .map_err(|e| {
-                    log::error!("Error in stream: {e:?}");
-                    ErrorInternalServerError("Error processing stream")
-                })
-                .map(move |result| {
-                    result.and_then(|bytes| {
-                        let (transformed, last_is_thought) = transform_google_stream_to_openai(Ok(bytes), no_thought_process, prev_is_thought, data.markdown_thought);
-                        prev_is_thought = last_is_thought;
-                        transformed
-                    })
-                });
-                Ok(response.streaming(up_stream))
-            } else {
-                let body = upstream_response.body().await?;
-                log::info!("Got reply from Google: {}", String::from_utf8_lossy(&body));
-
-                let google_response: Value = serde_json::from_slice(&body)
-                    .map_err(|_| ErrorInternalServerError("Failed to parse Google response"))?;
-                
-                // Transform the Google response back to OpenAI format
-                let (openai_response, _) = transform_google_to_openai(&google_response, false, no_thought_process, false, data.markdown_thought);
-                if let Some(openai_response) = openai_response {
-                    log::info!("Replied to client: {openai_response}");
-                    Ok(response.json(openai_response))
-                } else {
-                    log::error!("Non stream mode but no choices available. Replied 503 to client");
-                    Ok(HttpResponse::ServiceUnavailable().body("Failed to connect to upstream server."))
+    // Transform the Google response back to OpenAI format
+    match transform_google_to_openai(&google_response, false, google_request.no_thought_process, google_request.thought_mode, google_request.image_output, state) {
+        Some(openai_response) => Ok(Ok(openai_response)),
+        None => {
+            log::error!("Non stream mode but no choices available. Replied 503 to client");
+            Ok(Err(HttpResponse::ServiceUnavailable().body("Failed to connect to upstream server.")))
+        }
+    }
+}
+
+/// Reshape an upstream error reply into a front end's error body, built by `wrap` from the status and message
+pub async fn rewrap_upstream_error(upstream_error: HttpResponse, wrap: impl FnOnce(StatusCode, String) -> Value) -> HttpResponse {
+    let status = upstream_error.status();
+    let body = actix_web::body::to_bytes(upstream_error.into_body()).await.unwrap_or_default();
+    // Gemini errors are {"error": {"code", "message", "status"}}
+    let message = serde_json::from_slice::<Value>(&body).ok()
+        .and_then(|error| error["error"]["message"].as_str().map(|m| m.to_string()))
+        .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+    HttpResponse::build(status).json(wrap(status, message))
+}
+
+/// Turns the chat.completion.chunk values of a stream into the SSE events of a front end API
+pub trait ChunkEncoder {
+    /// SSE text for one chunk, or for an error object when the stream cannot continue
+    fn encode(&mut self, chunk: Value) -> String;
+    /// SSE text closing the stream
+    fn finish(&mut self) -> String;
+}
+
+/// OpenAI chat completions stream
+pub struct ChatChunkEncoder;
+
+impl ChunkEncoder for ChatChunkEncoder {
+    fn encode(&mut self, chunk: Value) -> String {
+        format!("data: {chunk}\n\n")
+    }
+
+    fn finish(&mut self) -> String {
+        // Gemini never sends [DONE]
+        "data: [DONE]\n\n".to_string()
+    }
+}
+
+/// Translate an upstream SSE body into the stream of a front end API
+pub fn relay_google_stream(
+    upstream_response: UpstreamResponse,
+    google_request: &GoogleRequest,
+    mut state: StreamState,
+    mut encoder: impl ChunkEncoder + 'static,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    let no_thought_process = google_request.no_thought_process;
+    let thought_mode = google_request.thought_mode;
+    let image_output = google_request.image_output;
+    let mut sse_decoder = SseDecoder::default();
+    let mut aborted = false;
+    // A trailing None marks the end of the upstream body so buffered events get flushed
+    upstream_response
+    .map(Some)
+    .chain(futures_util::stream::once(async { None }))
+    .map(move |item| {
+        let chunks = match item {
+            Some(Ok(bytes)) => transform_google_stream_to_openai(sse_decoder.feed(&bytes), no_thought_process, thought_mode, image_output, &mut state),
+            Some(Err(e)) => {
+                log::error!("Error in stream: {e:?}");
+                aborted = true;
+                vec![openai_stream_error(&format!("Upstream stream aborted: {e}"))]
+            },
+            None => {
+                // A cut off answer gets an error instead of a closing finish_reason. Gemini ends every
+                // candidate with a finishReason, a dropped connection can also just end the body early.
+                let mut chunks = Vec::new();
+                if !aborted {
+                    chunks = transform_google_stream_to_openai(sse_decoder.finish(), no_thought_process, thought_mode, image_output, &mut state);
+                    if state.choices.values().any(|choice| choice.finish_reason.is_none()) {
+                        log::error!("Upstream stream ended before every candidate finished");
+                        chunks.push(openai_stream_error("Upstream stream ended before the answer finished"));
+                    } else {
+                        chunks.extend(finish_google_stream_to_openai(&mut state));
+                    }
                 }
+                let mut output: String = chunks.into_iter().map(|chunk| encoder.encode(chunk)).collect();
+                output.push_str(&encoder.finish());
+                return Ok(Bytes::from(output));
+            },
+        };
+        let output: String = chunks.into_iter().map(|chunk| encoder.encode(chunk)).collect();
+        Ok(Bytes::from(output))
+    })
+}
+
+/// Unix timestamp for the `created` field
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub async fn reverse_proxy(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    if !req.path().starts_with("/v1/chat/completions") {
+        return Ok(HttpResponse::NotFound().body("Not Found"));
+    }
+
+    log::info!("Got request: {}", String::from_utf8_lossy(&body_data));
+
+    let json_body: Value = serde_json::from_slice(&body_data)
+        .map_err(|_| ErrorInternalServerError("Failed to parse JSON body"))?;
+
+    let mut google_request = build_google_request(&req, &json_body, &data, &client).await?;
+    // Audio is spoken from the complete reply, a stream is replayed from it
+    let replay_stream = google_request.audio_output.is_some() && google_request.is_stream;
+    google_request.is_stream &= !replay_stream;
+    let upstream_response = send_google_request(&google_request, &data, &client).await?;
+
+    let mut state = StreamState::new(new_chat_completion_id(), unix_timestamp(), data.thought_signatures.clone());
+    state.include_usage = google_request.include_usage;
+
+    if upstream_response.content_type().contains("text/event-stream") {
+        let mut response = response_builder_from(&upstream_response);
+        Ok(response.streaming(relay_google_stream(upstream_response, &google_request, state, ChatChunkEncoder)))
+    } else {
+        let mut openai_response = match receive_google_response(upstream_response, &google_request, &data.upload_cache, &mut state).await? {
+            Ok(openai_response) => openai_response,
+            Err(upstream_error) => return Ok(upstream_error),
+        };
+        if let Some(audio_output) = &google_request.audio_output {
+            let spoken = speak_chat_completion(
+                &client, &data.upstream_url, &google_request.api_key, &audio_output.voice, &audio_output.format, state.created, &mut openai_response,
+            ).await?;
+            if let Err(upstream_error) = spoken {
+                return Ok(upstream_error);
             }
         }
-        Err(err) => {
-            log::error!("Failed to forward request: {err:?}");
-            Ok(HttpResponse::InternalServerError().body("Failed to connect to upstream server."))
+        log::info!("Replied to client: {openai_response}");
+        if replay_stream {
+            let mut encoder = ChatChunkEncoder;
+            let mut body: String = completion_to_chunks(&openai_response, google_request.include_usage).into_iter()
+                .map(|chunk| encoder.encode(chunk))
+                .collect();
+            body.push_str(&encoder.finish());
+            return Ok(HttpResponse::Ok().content_type("text/event-stream").body(body));
         }
+        Ok(HttpResponse::Ok().json(openai_response))
+    }
+}
+
+// Replay a chat.completion as the chunks of a stream
+fn completion_to_chunks(completion: &Value, include_usage: bool) -> Vec<Value> {
+    let empty = vec![];
+    let choices: Vec<Value> = completion["choices"].as_array().unwrap_or(&empty).iter()
+        .map(|choice| {
+            let mut delta = choice["message"].clone();
+            if let Some(tool_calls) = delta.get_mut("tool_calls").and_then(Value::as_array_mut) {
+                for (index, tool_call) in tool_calls.iter_mut().enumerate() {
+                    tool_call["index"] = json!(index);
+                }
+            }
+            json!({ "index": choice["index"], "delta": delta, "finish_reason": choice["finish_reason"] })
+        })
+        .collect();
+    let chunk = |choices: Value| json!({
+        "id": completion["id"],
+        "object": "chat.completion.chunk",
+        "created": completion["created"],
+        "model": completion["model"],
+        "choices": choices
+    });
+    let mut chunks = vec![chunk(json!(choices))];
+    if include_usage {
+        let mut usage_chunk = chunk(json!([]));
+        usage_chunk["usage"] = completion["usage"].clone();
+        chunks.push(usage_chunk);
     }
+    chunks
 }
@@ -0,0 +1,611 @@
+use crate::app_state::AppState;
+use crate::blocks::{Block, BlockAccumulator, BlockEvent, TextKind};
+use crate::proxy::{
+    build_google_request, receive_google_response, relay_google_stream, response_builder_from, rewrap_upstream_error, send_google_request, unix_timestamp, ChunkEncoder,
+};
+use crate::transformers::StreamState;
+use actix_web::{error::ErrorBadRequest, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use awc::Client;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// Responses kept before the oldest are evicted
+const RESPONSE_STORE_CAPACITY: usize = 10_000;
+
+/// What a response adds to its conversation: the chat messages of its own input and output,
+/// and the response it continues. The full conversation is rebuilt by walking the chain.
+#[derive(Clone)]
+struct StoredResponse {
+    previous_response_id: Option<String>,
+    messages: Vec<Value>,
+}
+
+impl StoredResponse {
+    fn to_json(&self) -> Value {
+        json!({ "previous_response_id": self.previous_response_id, "messages": self.messages })
+    }
+
+    fn from_json(json: &Value) -> Option<Self> {
+        Some(Self {
+            previous_response_id: json.get("previous_response_id").and_then(Value::as_str).map(|id| id.to_string()),
+            messages: json.get("messages")?.as_array()?.clone(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct StoreEntries {
+    responses: HashMap<String, StoredResponse>,
+    // Every stored id, persisted ones included, oldest first
+    order: VecDeque<String>,
+}
+
+/// Stored responses, as chat messages, to resolve `previous_response_id`.
+/// Kept in memory and, when a directory is configured, also written there as `<id>.json`.
+pub struct ResponseStore {
+    entries: Mutex<StoreEntries>,
+    persist_dir: Option<PathBuf>,
+}
+
+impl ResponseStore {
+    pub fn new(persist_dir: Option<PathBuf>) -> Self {
+        let mut entries = StoreEntries::default();
+        if let Some(dir) = &persist_dir {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                log::error!("Failed to create response store directory {}: {e}", dir.display());
+            }
+            // Responses persisted by earlier runs count towards the capacity, oldest first
+            let mut persisted: Vec<(std::time::SystemTime, String)> = std::fs::read_dir(dir).into_iter().flatten()
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let id = entry.file_name().to_str()?.strip_suffix(".json")?.to_string();
+                    Some((entry.metadata().ok()?.modified().ok()?, id))
+                })
+                .collect();
+            persisted.sort();
+            entries.order.extend(persisted.into_iter().map(|(_, id)| id));
+        }
+        let store = Self {
+            entries: Mutex::new(entries),
+            persist_dir,
+        };
+        store.evict();
+        store
+    }
+
+    // Ids come from clients, keep them from escaping the store directory
+    fn persist_path(&self, id: &str) -> Option<PathBuf> {
+        let valid_id = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        self.persist_dir.as_ref().filter(|_| valid_id).map(|dir| dir.join(format!("{id}.json")))
+    }
+
+    async fn get_one(&self, id: &str) -> Option<StoredResponse> {
+        if let Some(stored) = self.entries.lock().unwrap().responses.get(id) {
+            return Some(stored.clone());
+        }
+        let stored = tokio::fs::read(self.persist_path(id)?).await.ok()?;
+        StoredResponse::from_json(&serde_json::from_slice(&stored).ok()?)
+    }
+
+    /// The conversation up to and including response `id`, None when it or one of its predecessors is gone
+    pub async fn get(&self, id: &str) -> Option<Vec<Value>> {
+        let mut chain = Vec::new();
+        let mut next = Some(id.to_string());
+        while let Some(id) = next {
+            let Some(stored) = self.get_one(&id).await else {
+                if !chain.is_empty() {
+                    log::warn!("Response {id} of a previous_response_id chain is no longer stored");
+                }
+                return None;
+            };
+            next = stored.previous_response_id;
+            chain.push(stored.messages);
+        }
+        Some(chain.into_iter().rev().flatten().collect())
+    }
+
+    pub fn insert(&self, id: String, previous_response_id: Option<String>, messages: Vec<Value>) {
+        let stored = StoredResponse { previous_response_id, messages };
+        if let Some(path) = self.persist_path(&id) {
+            let contents = stored.to_json().to_string();
+            let id = id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = tokio::fs::write(&path, contents).await {
+                    log::error!("Failed to persist response {id}: {e}");
+                }
+            });
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.responses.insert(id.clone(), stored).is_none() {
+            entries.order.push_back(id);
+        }
+        drop(entries);
+        self.evict();
+    }
+
+    // Drop the oldest responses past the capacity, from memory and from disk
+    fn evict(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        while entries.order.len() > RESPONSE_STORE_CAPACITY {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+            entries.responses.remove(&oldest);
+            if let Some(path) = self.persist_path(&oldest) {
+                tokio::spawn(async move {
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        log::warn!("Failed to delete evicted response {}: {e}", path.display());
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn new_item_id(prefix: &str) -> String {
+    format!("{prefix}_{}", uuid::Uuid::new_v4().simple())
+}
+
+// Convert Responses content parts into chat content parts
+fn transform_responses_content_to_chat(content: &Value) -> Value {
+    let Some(parts) = content.as_array() else {
+        return content.clone();
+    };
+    let chat_parts: Vec<Value> = parts.iter().filter_map(|part| {
+        match part.get("type").and_then(Value::as_str) {
+            Some("input_text") | Some("output_text") | Some("text") => Some(json!({ "type": "text", "text": part["text"] })),
+            Some("refusal") => Some(json!({ "type": "text", "text": part["refusal"] })),
+            Some("input_image") => {
+                let url = part.get("image_url").or_else(|| part.get("file_id")).cloned().unwrap_or(Value::Null);
+                Some(json!({ "type": "image_url", "image_url": { "url": url } }))
+            },
+            Some("input_file") => Some(json!({
+                "type": "file",
+                "file": {
+                    "file_data": part.get("file_data").or_else(|| part.get("file_url")).cloned().unwrap_or(Value::Null),
+                    "file_id": part.get("file_id").cloned().unwrap_or(Value::Null),
+                    "filename": part.get("filename").cloned().unwrap_or(Value::Null)
+                }
+            })),
+            other => {
+                log::warn!("Unknown Responses content part type: {other:?}");
+                None
+            }
+        }
+    }).collect();
+    json!(chat_parts)
+}
+
+fn content_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().filter_map(|p| p.get("text").and_then(Value::as_str)).collect::<Vec<&str>>().join("\n"),
+        _ => String::new(),
+    }
+}
+
+// Convert Responses input items into chat messages, system and developer texts are collected apart
+fn transform_responses_input_to_chat(input: Option<&Value>, system_texts: &mut Vec<String>) -> Result<Vec<Value>, Error> {
+    let items = match input {
+        None => return Ok(Vec::new()),
+        Some(Value::String(text)) => return Ok(vec![json!({ "role": "user", "content": text })]),
+        Some(Value::Array(items)) => items,
+        Some(_) => return Err(ErrorBadRequest("input must be a string or an array of items")),
+    };
+
+    let mut messages: Vec<Value> = Vec::new();
+    for item in items {
+        let item_type = item.get("type").and_then(Value::as_str).unwrap_or("message");
+        match item_type {
+            "message" => {
+                let role = item.get("role").and_then(Value::as_str).unwrap_or("user");
+                let content = item.get("content").unwrap_or(&Value::Null);
+                if role == "system" || role == "developer" {
+                    system_texts.push(content_text(content));
+                } else {
+                    messages.push(json!({ "role": role, "content": transform_responses_content_to_chat(content) }));
+                }
+            },
+            "function_call" => {
+                let tool_call = json!({
+                    "id": item["call_id"],
+                    "type": "function",
+                    "function": { "name": item["name"], "arguments": item["arguments"] }
+                });
+                // Parallel calls belong to the same assistant turn
+                match messages.last_mut() {
+                    Some(last) if last["role"] == "assistant" && last.get("tool_calls").is_some() => {
+                        last["tool_calls"].as_array_mut().unwrap().push(tool_call);
+                    },
+                    _ => messages.push(json!({ "role": "assistant", "content": null, "tool_calls": [tool_call] })),
+                }
+            },
+            "function_call_output" => {
+                let output = match &item["output"] {
+                    Value::String(output) => output.clone(),
+                    other => content_text(other),
+                };
+                messages.push(json!({ "role": "tool", "tool_call_id": item["call_id"], "content": output }));
+            },
+            "reasoning" => log::debug!("Reasoning input item skipped"),
+            other => return Err(ErrorBadRequest(format!("Unsupported input item type: {other}"))),
+        }
+    }
+    Ok(messages)
+}
+
+fn transform_responses_tools_to_chat(tools: &[Value]) -> Vec<Value> {
+    tools.iter()
+        .filter(|tool| tool.get("type").and_then(Value::as_str) == Some("function"))
+        .map(|tool| {
+            let mut function = json!({ "name": tool["name"] });
+            for field in ["description", "parameters"] {
+                if let Some(value) = tool.get(field).filter(|v| !v.is_null()) {
+                    function[field] = value.clone();
+                }
+            }
+            json!({ "type": "function", "function": function })
+        })
+        .collect()
+}
+
+fn transform_responses_tool_choice_to_chat(tool_choice: &Value) -> Value {
+    match tool_choice.get("name") {
+        Some(name) => json!({ "type": "function", "function": { "name": name } }),
+        None => tool_choice.clone(),
+    }
+}
+
+// Build the chat completions body for a Responses request, also returning the messages of its own input
+fn responses_to_chat_request(json_body: &Value, history: Vec<Value>) -> Result<(Value, Vec<Value>), Error> {
+    let mut system_texts: Vec<String> = json_body.get("instructions").and_then(Value::as_str).map(|i| vec![i.to_string()]).unwrap_or_default();
+    let input_messages = transform_responses_input_to_chat(json_body.get("input"), &mut system_texts)?;
+
+    let mut messages = Vec::new();
+    if !system_texts.is_empty() {
+        messages.push(json!({ "role": "system", "content": system_texts.join("\n\n") }));
+    }
+    messages.extend(history);
+    messages.extend(input_messages.iter().cloned());
+
+    let mut chat_body = json!({
+        "model": json_body["model"],
+        "messages": messages,
+        "stream": json_body.get("stream").cloned().unwrap_or(json!(false)),
+        // Usage goes into response.completed
+        "stream_options": { "include_usage": true },
+        "thought_mode": "reasoning_content"
+    });
+    for (from, to) in [("max_output_tokens", "max_tokens"), ("temperature", "temperature"), ("top_p", "top_p")] {
+        if let Some(value) = json_body.get(from) {
+            chat_body[to] = value.clone();
+        }
+    }
+    if let Some(effort) = json_body.get("reasoning").and_then(|r| r.get("effort")) {
+        chat_body["reasoning_effort"] = effort.clone();
+    }
+    if let Some(tools) = json_body.get("tools").and_then(Value::as_array) {
+        chat_body["tools"] = json!(transform_responses_tools_to_chat(tools));
+    }
+    if let Some(tool_choice) = json_body.get("tool_choice") {
+        chat_body["tool_choice"] = transform_responses_tool_choice_to_chat(tool_choice);
+    }
+    if let Some(format) = json_body.get("text").and_then(|t| t.get("format")) {
+        chat_body["response_format"] = match format.get("type").and_then(Value::as_str) {
+            Some("json_schema") => json!({ "type": "json_schema", "json_schema": { "name": format["name"], "schema": format["schema"] } }),
+            _ => format.clone(),
+        };
+    }
+    Ok((chat_body, input_messages))
+}
+
+fn transform_chat_usage_to_responses(usage: &Value) -> Value {
+    json!({
+        "input_tokens": usage["prompt_tokens"],
+        "input_tokens_details": { "cached_tokens": usage["prompt_tokens_details"]["cached_tokens"] },
+        "output_tokens": usage["completion_tokens"],
+        "output_tokens_details": { "reasoning_tokens": usage["completion_tokens_details"]["reasoning_tokens"] },
+        "total_tokens": usage["total_tokens"]
+    })
+}
+
+fn responses_error(status: u16, message: Value) -> Value {
+    let error_type = if status >= 500 { "server_error" } else { "invalid_request_error" };
+    json!({ "error": { "message": message, "type": error_type, "param": null, "code": null } })
+}
+
+/// Builds a Responses object from chat deltas, emitting the typed streaming events on the way
+struct ResponsesEncoder {
+    response: Value,
+    blocks: BlockAccumulator,
+    // Output item id of each block
+    item_ids: Vec<String>,
+    sequence_number: u64,
+    started: bool,
+    failed: Option<Value>,
+    usage: Option<Value>,
+    store: Option<Arc<ResponseStore>>,
+    input_messages: Vec<Value>,
+}
+
+impl ResponsesEncoder {
+    fn event(&mut self, event_type: &str, mut payload: Value) -> String {
+        payload["type"] = json!(event_type);
+        payload["sequence_number"] = json!(self.sequence_number);
+        self.sequence_number += 1;
+        format!("event: {event_type}\ndata: {payload}\n\n")
+    }
+
+    fn start(&mut self) -> String {
+        if self.started {
+            return String::new();
+        }
+        self.started = true;
+        let response = self.response.clone();
+        let mut events = self.event("response.created", json!({ "response": response }));
+        events.push_str(&self.event("response.in_progress", json!({ "response": response })));
+        events
+    }
+
+    // Output item of a block, empty while in progress as announced by output_item.added
+    fn output_item(&self, index: usize, in_progress: bool) -> Value {
+        let id = &self.item_ids[index];
+        let status = if in_progress { "in_progress" } else { "completed" };
+        match &self.blocks.blocks[index] {
+            Block::Text { kind, text } => {
+                let text = if in_progress { "" } else { text.as_str() };
+                match kind {
+                    TextKind::Reasoning => json!({
+                        "id": id, "type": "reasoning", "status": status,
+                        "summary": [{ "type": "summary_text", "text": text }]
+                    }),
+                    TextKind::Text => json!({
+                        "id": id, "type": "message", "status": status, "role": "assistant",
+                        "content": [{ "type": "output_text", "text": text, "annotations": [] }]
+                    }),
+                }
+            },
+            Block::ToolCall { id: call_id, name, arguments } => json!({
+                "id": id, "type": "function_call", "status": status, "call_id": call_id, "name": name,
+                "arguments": if in_progress { "" } else { arguments.as_str() }
+            }),
+        }
+    }
+
+    fn block_events(&mut self, events: Vec<BlockEvent>) -> String {
+        let mut output = String::new();
+        for event in events {
+            match event {
+                BlockEvent::Opened(output_index) => {
+                    let is_reasoning = matches!(self.blocks.blocks[output_index], Block::Text { kind: TextKind::Reasoning, .. });
+                    self.item_ids.push(new_item_id(if is_reasoning { "rs" } else { "msg" }));
+                    let item = self.output_item(output_index, true);
+                    let item_id = item["id"].clone();
+                    output.push_str(&self.event("response.output_item.added", json!({ "output_index": output_index, "item": item })));
+                    output.push_str(&if is_reasoning {
+                        self.event("response.reasoning_summary_part.added", json!({
+                            "item_id": item_id, "output_index": output_index, "summary_index": 0,
+                            "part": { "type": "summary_text", "text": "" }
+                        }))
+                    } else {
+                        self.event("response.content_part.added", json!({
+                            "item_id": item_id, "output_index": output_index, "content_index": 0,
+                            "part": { "type": "output_text", "text": "", "annotations": [] }
+                        }))
+                    });
+                },
+                BlockEvent::Delta(output_index, delta) => {
+                    let item_id = self.item_ids[output_index].clone();
+                    output.push_str(&match self.blocks.blocks[output_index] {
+                        Block::Text { kind: TextKind::Reasoning, .. } => self.event("response.reasoning_summary_text.delta", json!({
+                            "item_id": item_id, "output_index": output_index, "summary_index": 0, "delta": delta
+                        })),
+                        _ => self.event("response.output_text.delta", json!({
+                            "item_id": item_id, "output_index": output_index, "content_index": 0, "delta": delta
+                        })),
+                    });
+                },
+                BlockEvent::Closed(output_index) => {
+                    let item = self.output_item(output_index, false);
+                    let item_id = item["id"].clone();
+                    if item["type"] == "reasoning" {
+                        let part = item["summary"][0].clone();
+                        output.push_str(&self.event("response.reasoning_summary_text.done", json!({
+                            "item_id": item_id, "output_index": output_index, "summary_index": 0, "text": part["text"]
+                        })));
+                        output.push_str(&self.event("response.reasoning_summary_part.done", json!({
+                            "item_id": item_id, "output_index": output_index, "summary_index": 0, "part": part
+                        })));
+                    } else {
+                        let part = item["content"][0].clone();
+                        output.push_str(&self.event("response.output_text.done", json!({
+                            "item_id": item_id, "output_index": output_index, "content_index": 0, "text": part["text"]
+                        })));
+                        output.push_str(&self.event("response.content_part.done", json!({
+                            "item_id": item_id, "output_index": output_index, "content_index": 0, "part": part
+                        })));
+                    }
+                    output.push_str(&self.event("response.output_item.done", json!({ "output_index": output_index, "item": item })));
+                },
+                BlockEvent::ToolCall(output_index) => {
+                    self.item_ids.push(new_item_id("fc"));
+                    let added = self.output_item(output_index, true);
+                    let item = self.output_item(output_index, false);
+                    let item_id = item["id"].clone();
+                    output.push_str(&self.event("response.output_item.added", json!({ "output_index": output_index, "item": added })));
+                    output.push_str(&self.event("response.function_call_arguments.delta", json!({
+                        "item_id": item_id, "output_index": output_index, "delta": item["arguments"]
+                    })));
+                    output.push_str(&self.event("response.function_call_arguments.done", json!({
+                        "item_id": item_id, "output_index": output_index, "arguments": item["arguments"]
+                    })));
+                    output.push_str(&self.event("response.output_item.done", json!({ "output_index": output_index, "item": item })));
+                },
+            }
+        }
+        output
+    }
+
+    fn push_chat_choice(&mut self, choice: &Value, message_key: &str) -> String {
+        let events = self.blocks.push_chat_choice(choice, message_key);
+        self.block_events(events)
+    }
+
+    fn final_response(&self) -> Value {
+        let mut response = self.response.clone();
+        response["output"] = (0..self.blocks.blocks.len()).map(|index| self.output_item(index, false)).collect();
+        response["usage"] = self.usage.as_ref().map(transform_chat_usage_to_responses).unwrap_or(Value::Null);
+        let incomplete_reason = match self.blocks.finish_reason.as_deref() {
+            Some("length") => Some("max_output_tokens"),
+            Some("content_filter") => Some("content_filter"),
+            _ => None,
+        };
+        if let Some(error) = &self.failed {
+            response["status"] = json!("failed");
+            response["error"] = json!({ "code": "server_error", "message": error["error"]["message"] });
+        } else if let Some(reason) = incomplete_reason {
+            response["status"] = json!("incomplete");
+            response["incomplete_details"] = json!({ "reason": reason });
+        } else {
+            response["status"] = json!("completed");
+        }
+        response
+    }
+
+    // Remember this response's input and output for previous_response_id, failed responses cannot be continued
+    fn store_response(&mut self) {
+        let Some(store) = self.store.take().filter(|_| self.failed.is_none()) else {
+            return;
+        };
+        let mut text = String::new();
+        let mut tool_calls = Vec::new();
+        for block in &self.blocks.blocks {
+            match block {
+                Block::Text { kind: TextKind::Text, text: block_text } => text.push_str(block_text),
+                Block::Text { kind: TextKind::Reasoning, .. } => {},
+                Block::ToolCall { id, name, arguments } => tool_calls.push(json!({
+                    "id": id,
+                    "type": "function",
+                    "function": { "name": name, "arguments": arguments }
+                })),
+            }
+        }
+        let mut assistant_message = json!({ "role": "assistant", "content": if text.is_empty() { Value::Null } else { json!(text) } });
+        if !tool_calls.is_empty() {
+            assistant_message["tool_calls"] = json!(tool_calls);
+        }
+        let mut messages = std::mem::take(&mut self.input_messages);
+        messages.push(assistant_message);
+        let previous_response_id = self.response["previous_response_id"].as_str().map(|id| id.to_string());
+        store.insert(self.response["id"].as_str().unwrap_or("").to_string(), previous_response_id, messages);
+    }
+
+    fn complete(&mut self) -> String {
+        let mut events = self.start();
+        let closed = self.blocks.close();
+        events.push_str(&self.block_events(closed));
+        self.store_response();
+        let response = self.final_response();
+        let event_type = if self.failed.is_some() { "response.failed" } else { "response.completed" };
+        events.push_str(&self.event(event_type, json!({ "response": response })));
+        events
+    }
+}
+
+impl ChunkEncoder for ResponsesEncoder {
+    fn encode(&mut self, chunk: Value) -> String {
+        let mut events = self.start();
+        if let Some(error) = chunk.get("error") {
+            events.push_str(&self.event("error", json!({
+                "code": error.get("code").cloned().unwrap_or(Value::Null),
+                "message": error.get("message").cloned().unwrap_or(Value::Null),
+                "param": null
+            })));
+            self.failed = Some(chunk);
+            return events;
+        }
+        if let Some(usage) = chunk.get("usage") {
+            self.usage = Some(usage.clone());
+        }
+        if let Some(choice) = chunk.get("choices").and_then(Value::as_array).and_then(|choices| choices.first()) {
+            events.push_str(&self.push_chat_choice(choice, "delta"));
+        }
+        events
+    }
+
+    fn finish(&mut self) -> String {
+        self.complete()
+    }
+}
+
+/// POST /v1/responses
+pub async fn responses(
+    req: HttpRequest,
+    body_data: web::Bytes,
+    data: web::Data<AppState>,
+    client: web::Data<Client>,
+) -> Result<HttpResponse, Error> {
+    log::info!("Got responses request: {}", String::from_utf8_lossy(&body_data));
+
+    let json_body: Value = serde_json::from_slice(&body_data)
+        .map_err(|_| ErrorBadRequest("Failed to parse JSON body"))?;
+
+    let previous_response_id = json_body.get("previous_response_id").and_then(Value::as_str);
+    let history = match previous_response_id {
+        Some(id) => data.response_store.get(id).await
+            .ok_or_else(|| ErrorBadRequest(format!("Previous response with id '{id}' not found")))?,
+        None => Vec::new(),
+    };
+    let (chat_body, input_messages) = responses_to_chat_request(&json_body, history)?;
+    let google_request = build_google_request(&req, &chat_body, &data, &client).await?;
+    let upstream_response = send_google_request(&google_request, &data, &client).await?;
+
+    let response_id = new_item_id("resp");
+    let created_at = unix_timestamp();
+    let store = json_body.get("store").and_then(Value::as_bool).unwrap_or(true);
+    let mut encoder = ResponsesEncoder {
+        response: json!({
+            "id": response_id,
+            "object": "response",
+            "created_at": created_at,
+            "status": "in_progress",
+            "model": json_body["model"],
+            "instructions": json_body.get("instructions").cloned().unwrap_or(Value::Null),
+            "previous_response_id": previous_response_id,
+            "tools": json_body.get("tools").cloned().unwrap_or(json!([])),
+            "output": [],
+            "usage": null
+        }),
+        blocks: BlockAccumulator::new(false),
+        item_ids: Vec::new(),
+        sequence_number: 0,
+        started: false,
+        failed: None,
+        usage: None,
+        store: store.then(|| data.response_store.clone()),
+        input_messages,
+    };
+    let mut state = StreamState::new(response_id, created_at, data.thought_signatures.clone());
+    state.include_usage = true;
+
+    if upstream_response.content_type().contains("text/event-stream") {
+        let mut response = response_builder_from(&upstream_response);
+        Ok(response.streaming(relay_google_stream(upstream_response, &google_request, state, encoder)))
+    } else {
+        match receive_google_response(upstream_response, &google_request, &data.upload_cache, &mut state).await? {
+            Ok(chat_response) => {
+                if let Some(choice) = chat_response.get("choices").and_then(Value::as_array).and_then(|choices| choices.first()) {
+                    encoder.push_chat_choice(choice, "message");
+                }
+                encoder.usage = chat_response.get("usage").cloned();
+                encoder.complete();
+                let response = encoder.final_response();
+                log::info!("Replied to client: {response}");
+                Ok(HttpResponse::Ok().json(response))
+            },
+            Err(upstream_error) => Ok(rewrap_upstream_error(upstream_error, |status, message| responses_error(status.as_u16(), json!(message))).await),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use actix_web::{error::ErrorBadRequest, Error};
+use serde_json::{json, Map, Value};
+
+// Keywords of Gemini's Schema object that are copied over unchanged
+const PASSTHROUGH_KEYWORDS: [&str; 13] = [
+    "title",
+    "description",
+    "default",
+    "example",
+    "minItems",
+    "maxItems",
+    "minProperties",
+    "maxProperties",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "maximum",
+];
+
+const GOOGLE_TYPES: [&str; 6] = ["string", "number", "integer", "boolean", "array", "object"];
+
+// The only `format` values Gemini accepts, per type
+const STRING_FORMATS: [&str; 2] = ["enum", "date-time"];
+const NUMBER_FORMATS: [&str; 4] = ["int32", "int64", "float", "double"];
+
+/// Rewrite a JSON Schema into the OpenAPI subset accepted by Gemini `responseSchema` and `functionDeclarations`.
+/// `$ref`s are inlined, `anyOf` with `null` becomes `nullable` and keywords Gemini rejects are dropped.
+/// Schemas that cannot be represented (recursive refs, tuples, multi-member `allOf`...) are reported as 400.
+pub fn json_schema_to_google(schema: &Value) -> Result<Value, Error> {
+    convert_schema(schema, schema, &mut Vec::new())
+}
+
+fn unrepresentable(reason: String) -> Error {
+    log::warn!("Schema rejected: {reason}");
+    ErrorBadRequest(format!("JSON schema cannot be represented for Gemini: {reason}"))
+}
+
+fn resolve_ref<'a>(root: &'a Value, reference: &str) -> Result<&'a Value, Error> {
+    reference.strip_prefix('#')
+        .and_then(|pointer| root.pointer(pointer))
+        .ok_or_else(|| unrepresentable(format!("unresolvable $ref \"{reference}\"")))
+}
+
+fn convert_schema(schema: &Value, root: &Value, ref_stack: &mut Vec<String>) -> Result<Value, Error> {
+    let schema_object = match schema {
+        Value::Object(schema_object) => schema_object,
+        other => return Err(unrepresentable(format!("unsupported schema {other}"))),
+    };
+
+    if let Some(reference) = schema_object.get("$ref").and_then(Value::as_str) {
+        if ref_stack.iter().any(|r| r == reference) {
+            return Err(unrepresentable(format!("recursive $ref \"{reference}\"")));
+        }
+        ref_stack.push(reference.to_string());
+        let resolved = convert_schema(resolve_ref(root, reference)?, root, ref_stack);
+        ref_stack.pop();
+        let mut resolved = resolved?;
+        // Keep annotations written next to the $ref
+        if let Some(description) = schema_object.get("description") {
+            resolved["description"] = description.clone();
+        }
+        return Ok(resolved);
+    }
+
+    if let Some(all_of) = schema_object.get("allOf").and_then(Value::as_array) {
+        return match all_of.as_slice() {
+            [single] => convert_schema(single, root, ref_stack),
+            _ => Err(unrepresentable("allOf with more than one member".to_string())),
+        };
+    }
+
+    let mut converted = Map::new();
+    let mut nullable = schema_object.get("nullable").and_then(Value::as_bool).unwrap_or(false);
+
+    if let Some(variants) = schema_object.get("anyOf").or_else(|| schema_object.get("oneOf")).and_then(Value::as_array) {
+        let mut non_null_variants = Vec::new();
+        for variant in variants {
+            if variant.get("type").and_then(Value::as_str) == Some("null") {
+                nullable = true;
+            } else {
+                non_null_variants.push(convert_schema(variant, root, ref_stack)?);
+            }
+        }
+        match non_null_variants.len() {
+            0 => return Err(unrepresentable("anyOf with only null".to_string())),
+            1 => {
+                if let Value::Object(single) = non_null_variants.pop().unwrap() {
+                    converted.extend(single);
+                }
+            },
+            _ => {
+                converted.insert("anyOf".to_string(), json!(non_null_variants));
+            }
+        }
+    }
+
+    match schema_object.get("type") {
+        Some(Value::String(type_name)) if GOOGLE_TYPES.contains(&type_name.as_str()) => {
+            converted.insert("type".to_string(), json!(type_name));
+        },
+        Some(Value::Array(type_names)) => {
+            let mut non_null_types = Vec::new();
+            for type_name in type_names.iter().filter_map(Value::as_str) {
+                if type_name == "null" {
+                    nullable = true;
+                } else if GOOGLE_TYPES.contains(&type_name) {
+                    non_null_types.push(type_name);
+                } else {
+                    return Err(unrepresentable(format!("unsupported type \"{type_name}\"")));
+                }
+            }
+            match non_null_types.as_slice() {
+                [] => return Err(unrepresentable("type with only null".to_string())),
+                [type_name] => {
+                    converted.insert("type".to_string(), json!(type_name));
+                },
+                _ => {
+                    let variants: Vec<Value> = non_null_types.iter().map(|t| json!({ "type": t })).collect();
+                    converted.insert("anyOf".to_string(), json!(variants));
+                }
+            }
+        },
+        Some(other) => return Err(unrepresentable(format!("unsupported type {other}"))),
+        None => {},
+    }
+
+    let constant = schema_object.get("const").map(|constant| vec![constant.clone()]);
+    if let Some(values) = constant.or_else(|| schema_object.get("enum").and_then(Value::as_array).cloned()) {
+        // Gemini enums are strings only, other values are stringified and the field becomes a string
+        let values: Vec<Value> = values.into_iter()
+            .filter(|value| {
+                nullable |= value.is_null();
+                !value.is_null()
+            })
+            .map(|value| match value {
+                Value::String(_) => value,
+                other => json!(other.to_string()),
+            })
+            .collect();
+        if !values.is_empty() {
+            converted.insert("enum".to_string(), json!(values));
+            converted.insert("type".to_string(), json!("string"));
+        }
+    }
+
+    // An empty properties map is rejected by Gemini, leave it out
+    if let Some(properties) = schema_object.get("properties").and_then(Value::as_object).filter(|p| !p.is_empty()) {
+        let mut converted_properties = Map::new();
+        for (name, property) in properties {
+            converted_properties.insert(name.clone(), convert_schema(property, root, ref_stack)?);
+        }
+        converted.insert("properties".to_string(), Value::Object(converted_properties));
+        if let Some(required) = schema_object.get("required") {
+            converted.insert("required".to_string(), required.clone());
+        }
+    }
+
+    match schema_object.get("items") {
+        Some(items @ Value::Object(_)) => {
+            converted.insert("items".to_string(), convert_schema(items, root, ref_stack)?);
+        },
+        Some(Value::Array(_)) => return Err(unrepresentable("tuple items".to_string())),
+        _ => {},
+    }
+    if schema_object.contains_key("prefixItems") {
+        return Err(unrepresentable("tuple prefixItems".to_string()));
+    }
+
+    for keyword in PASSTHROUGH_KEYWORDS {
+        if let Some(value) = schema_object.get(keyword) {
+            converted.entry(keyword.to_string()).or_insert_with(|| value.clone());
+        }
+    }
+
+    if let Some(format) = schema_object.get("format").and_then(Value::as_str) {
+        let supported = match converted.get("type").and_then(Value::as_str) {
+            Some("string") => STRING_FORMATS.contains(&format),
+            Some("number" | "integer") => NUMBER_FORMATS.contains(&format),
+            _ => false,
+        };
+        if supported {
+            converted.insert("format".to_string(), json!(format));
+        } else {
+            // Gemini rejects other formats, the model still sees the hint in the description
+            let description = match converted.get("description").and_then(Value::as_str) {
+                Some(description) => format!("{description} (format: {format})"),
+                None => format!("format: {format}"),
+            };
+            converted.insert("description".to_string(), json!(description));
+        }
+    }
+
+    if nullable {
+        converted.insert("nullable".to_string(), json!(true));
+    }
+
+    let rewritten = ["anyOf", "oneOf", "const", "enum", "format", "nullable"];
+    for dropped in schema_object.keys().filter(|k| !converted.contains_key(*k) && !rewritten.contains(&k.as_str())) {
+        log::debug!("Schema keyword \"{dropped}\" dropped for Gemini");
+    }
+
+    Ok(Value::Object(converted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inlines_refs() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "home": { "$ref": "#/$defs/address", "description": "Where they live" },
+                "work": { "$ref": "#/$defs/address" }
+            },
+            "$defs": {
+                "address": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                    "required": ["city"]
+                }
+            }
+        });
+        let converted = json_schema_to_google(&schema).unwrap();
+        assert_eq!(converted, json!({
+            "type": "object",
+            "properties": {
+                "home": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                    "required": ["city"],
+                    "description": "Where they live"
+                },
+                "work": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                    "required": ["city"]
+                }
+            }
+        }));
+    }
+
+    #[test]
+    fn rejects_recursive_refs() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": { "children": { "type": "array", "items": { "$ref": "#/$defs/node" } } }
+                }
+            }
+        });
+        let error = json_schema_to_google(&schema).unwrap_err();
+        assert!(error.to_string().contains("recursive $ref"));
+        assert_eq!(error.as_response_error().status_code(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn any_of_with_null_becomes_nullable() {
+        let schema = json!({ "anyOf": [{ "type": "string", "description": "Name" }, { "type": "null" }] });
+        assert_eq!(json_schema_to_google(&schema).unwrap(), json!({
+            "type": "string",
+            "description": "Name",
+            "nullable": true
+        }));
+
+        let schema = json!({ "anyOf": [{ "type": "string" }, { "type": "integer" }, { "type": "null" }] });
+        assert_eq!(json_schema_to_google(&schema).unwrap(), json!({
+            "anyOf": [{ "type": "string" }, { "type": "integer" }],
+            "nullable": true
+        }));
+    }
+
+    #[test]
+    fn keeps_supported_formats_only() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "at": { "type": "string", "format": "date-time" },
+                "count": { "type": "integer", "format": "int64" },
+                "mail": { "type": "string", "format": "email", "description": "Contact" },
+                "site": { "type": "string", "format": "uri" }
+            }
+        });
+        assert_eq!(json_schema_to_google(&schema).unwrap()["properties"], json!({
+            "at": { "type": "string", "format": "date-time" },
+            "count": { "type": "integer", "format": "int64" },
+            "mail": { "type": "string", "description": "Contact (format: email)" },
+            "site": { "type": "string", "description": "format: uri" }
+        }));
+    }
+
+    #[test]
+    fn stringifies_enum_values() {
+        let schema = json!({ "type": "integer", "enum": [1, 2, null] });
+        assert_eq!(json_schema_to_google(&schema).unwrap(), json!({
+            "type": "string",
+            "enum": ["1", "2"],
+            "nullable": true
+        }));
+    }
+}
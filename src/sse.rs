@@ -0,0 +1,132 @@
+/// Incremental decoder for an upstream `text/event-stream` body.
+/// Network reads can split events (and UTF-8 sequences) anywhere, so bytes are buffered until a
+/// blank line completes an event. Handles `\n`, `\r\n` and `\r` line endings, comments and
+/// multi-line `data:` fields; only the joined `data` of each complete event is returned.
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    data_lines: Vec<String>,
+}
+
+impl SseDecoder {
+    /// Buffer a chunk of the body and return the data of every event it completes
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(line) = self.next_line() {
+            self.process_line(&line, &mut events);
+        }
+        events
+    }
+
+    /// Flush what is left once the body has ended, even without a final blank line
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Some(line) = self.next_line() {
+            self.process_line(&line, &mut events);
+        }
+        let rest = std::mem::take(&mut self.buffer);
+        let rest = rest.strip_suffix(b"\r").unwrap_or(&rest);
+        if !rest.is_empty() {
+            self.process_line(rest, &mut events);
+        }
+        self.process_line(b"", &mut events);
+        events
+    }
+
+    fn next_line(&mut self) -> Option<Vec<u8>> {
+        let end = self.buffer.iter().position(|b| *b == b'\n' || *b == b'\r')?;
+        let terminator_len = if self.buffer[end] == b'\r' {
+            match self.buffer.get(end + 1) {
+                Some(b'\n') => 2,
+                Some(_) => 1,
+                // Could be the first half of a \r\n, wait for the next read
+                None => return None,
+            }
+        } else {
+            1
+        };
+        let line = self.buffer[..end].to_vec();
+        self.buffer.drain(..end + terminator_len);
+        Some(line)
+    }
+
+    fn process_line(&mut self, line: &[u8], events: &mut Vec<String>) {
+        if line.is_empty() {
+            if !self.data_lines.is_empty() {
+                events.push(self.data_lines.join("\n"));
+                self.data_lines.clear();
+            }
+            return;
+        }
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with(':') {
+            log::debug!("SSE comment: {line}");
+            return;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        // event, id and retry fields carry nothing Gemini relies on
+        if field == "data" {
+            self.data_lines.push(value.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode the body fed in one piece and byte by byte, both must agree
+    fn decode(body: &[u8]) -> Vec<String> {
+        let mut decoder = SseDecoder::default();
+        let mut whole = decoder.feed(body);
+        whole.extend(decoder.finish());
+
+        let mut decoder = SseDecoder::default();
+        let mut split: Vec<String> = body.iter().flat_map(|byte| decoder.feed(&[*byte])).collect();
+        split.extend(decoder.finish());
+
+        assert_eq!(whole, split);
+        whole
+    }
+
+    #[test]
+    fn splits_events_on_blank_lines() {
+        let body = b"data: {\"a\":1}\n\ndata: {\"b\":2}\n\n";
+        assert_eq!(decode(body), vec!["{\"a\":1}", "{\"b\":2}"]);
+    }
+
+    #[test]
+    fn keeps_utf8_split_across_reads() {
+        let body = "data: {\"text\":\"h\u{e9}llo \u{1f600}\"}\n\n".as_bytes();
+        assert_eq!(decode(body), vec!["{\"text\":\"h\u{e9}llo \u{1f600}\"}"]);
+    }
+
+    #[test]
+    fn handles_crlf_and_cr_line_endings() {
+        let body = b"data: one\r\n\r\ndata: two\r\rdata: three\n\n";
+        assert_eq!(decode(body), vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn joins_multi_line_data() {
+        let body = b"data: first\ndata:second\ndata\n\n";
+        assert_eq!(decode(body), vec!["first\nsecond\n"]);
+    }
+
+    #[test]
+    fn skips_comments_and_other_fields() {
+        let body = b": keep-alive\n\nevent: message\nid: 7\nretry: 100\ndata: payload\n\n";
+        assert_eq!(decode(body), vec!["payload"]);
+    }
+
+    #[test]
+    fn finish_flushes_event_without_trailing_blank_line() {
+        assert_eq!(decode(b"data: one\n\ndata: last"), vec!["one", "last"]);
+        assert_eq!(decode(b"data: last\r"), vec!["last"]);
+        assert_eq!(decode(b"data: last\r\n"), vec!["last"]);
+    }
+}
@@ -1,9 +1,18 @@
-use actix_web::{error::*, web::Bytes, Error};
+use actix_web::{error::*, Error};
 use serde_json::{json, Value};
 use awc::Client;
+use futures_util::future::try_join_all;
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD;
-use crate::proxy::ThinkingConfig;
+use crate::app_state::{AppState, ImageOutput, ThoughtMode};
+use crate::audio::pcm_to_wav;
+use crate::media::{audio_mime_type_from_format, mime_type_from_extension, parse_timestamp, MediaClient, MediaConfig, UploadCache, GOOGLE_FILES_URI_PREFIX};
+use crate::proxy::{ThinkingConfig, NO_THOUGHT_PROCESS_SUFFIX};
+use crate::schema::json_schema_to_google;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 // Extract MIME type and decode base64 to Vec<u8>
 fn decode_base64_and_get_mime_type(encoded_data: &str) -> Result<(String, Vec<u8>), Error> {
@@ -35,14 +44,14 @@ fn decode_base64_and_get_mime_type(encoded_data: &str) -> Result<(String, Vec<u8
     Ok((mime_type, decoded_data))
 }
 
-// Generic function for uploading files to Google
+// Generic function for uploading files to Google, returns the file URI and its expiration time
 async fn upload_to_google(
     client: &Client,
     api_key: &str,
     metadata: Value,
     file_data: Vec<u8>,
     mime_type: &str,
-) -> Result<String, Error> {
+) -> Result<(String, Option<SystemTime>), Error> {
     let upload_url = format!("https://generativelanguage.googleapis.com/upload/v1beta/files?key={api_key}");
 
     // Initiate upload
@@ -75,99 +84,523 @@ async fn upload_to_google(
 
     let file_uri = response["file"]["uri"].as_str()
         .ok_or(ErrorInternalServerError("File URI not returned"))?;
-    Ok(file_uri.to_string())
+    let expiration_time = response["file"]["expirationTime"].as_str().and_then(parse_timestamp);
+    Ok((file_uri.to_string(), expiration_time))
 }
 
-// Public function to upload base64 encoded image
-pub async fn upload_base64_image_to_google(
-    client: &Client,
-    api_key: &str,
-    base64_data: &str,
-) -> Result<(String, String), Error> {
-    let (mime_type, image_data) = decode_base64_and_get_mime_type(base64_data)?;
-    let metadata = json!({"file": {"display_name": "uploaded_image"}});
-    upload_to_google(client, api_key, metadata, image_data, &mime_type).await.map(|r| (mime_type, r))
+/// Serialized size a request is kept under by uploading media rather than inlining it, Gemini refuses requests over 20 MB
+const REQUEST_SIZE_BUDGET: usize = 19_000_000;
+
+/// Downloads, transcodes and uploads of one request running at once
+const MAX_CONCURRENT_MEDIA_TASKS: usize = 4;
+
+/// What converting the media of one request needs, shared by its concurrent conversions
+struct MediaContext<'a> {
+    client: &'a Client,
+    media_client: &'a MediaClient,
+    api_key: &'a str,
+    media: &'a MediaConfig,
+    upload_cache: &'a UploadCache,
+    /// Caps the slow media work, each step takes a slot of its own so none waits on another
+    slots: Semaphore,
+}
+
+impl<'a> MediaContext<'a> {
+    fn new(client: &'a Client, media_client: &'a MediaClient, api_key: &'a str, data: &'a AppState) -> Self {
+        Self {
+            client,
+            media_client,
+            api_key,
+            media: &data.media,
+            upload_cache: &data.upload_cache,
+            slots: Semaphore::new(MAX_CONCURRENT_MEDIA_TASKS),
+        }
+    }
+
+    async fn slot(&self) -> Result<SemaphorePermit<'_>, Error> {
+        self.slots.acquire().await.map_err(|_| ErrorInternalServerError("Media conversion was cancelled"))
+    }
+}
+
+/// Media bytes of a part, sent inline or uploaded once the size of the whole request is known
+struct PendingMedia {
+    data: Vec<u8>,
+    mime_type: String,
+    display_name: String,
+    video_metadata: Option<Value>,
+}
+
+/// A converted content part
+enum GooglePart {
+    Ready(Value),
+    Media(PendingMedia),
 }
 
-// Public function to upload base64 encoded audio
-pub async fn upload_base64_audio_to_google(
+/// Gemini part for audio sent outside a chat request, inline when small and otherwise uploaded once per API key and content
+pub async fn transform_audio_to_google(
     client: &Client,
+    media_client: &MediaClient,
     api_key: &str,
-    base64_data: &str,
-) -> Result<(String, String), Error> {
-    let (mime_type, audio_data) = decode_base64_and_get_mime_type(base64_data)?;
-    let metadata = json!({"file": {"display_name": "uploaded_audio"}});
-    upload_to_google(client, api_key, metadata, audio_data, &mime_type).await.map(|r| (mime_type, r))
-}
-
-pub fn transform_google_stream_to_openai(data: Result<Bytes, Error>, no_thought_process: bool, mut last_is_thought: bool, md_thought: bool) -> (Result<Bytes, Error>, bool) {
-    let data_r = data.and_then(|bytes| {
-        let input = String::from_utf8_lossy(&bytes);
-        log::info!("Got streaming data: {input}");
-
-        let events: Vec<&str> = input.split("\n\n").filter(|s| !s.is_empty()).collect();
-
-        let mut output = Vec::new();
-        for event in events {
-            if event.trim() == "data: [DONE]" {
-                output.push("data: [DONE]\n\n".to_string());
-            } else if let Some(json_str) = event.strip_prefix("data: ") {
-                match serde_json::from_str::<Value>(json_str) {
-                    Ok(json) => {
-                        let (openai_chunk, contains_thought) = transform_google_to_openai(&json, true, no_thought_process, last_is_thought, md_thought);
-                        last_is_thought = contains_thought;
-                        if let Some(openai_chunk) = openai_chunk {
-                            let transformed_event = format!(
-                                "data: {}\n\n",
-                                serde_json::to_string(&openai_chunk).map_err(|_| ErrorInternalServerError("Failed to serialize JSON"))?
-                            );
-                            output.push(transformed_event);
-                        }
-                    },
-                    Err(e) => log::error!("Failed to parse JSON: {e}"),
+    data: &AppState,
+    audio_data: Vec<u8>,
+    mime_type: &str,
+) -> Result<Value, Error> {
+    let context = MediaContext::new(client, media_client, api_key, data);
+    let audio = PendingMedia {
+        data: audio_data,
+        mime_type: mime_type.to_string(),
+        display_name: "uploaded_audio".to_string(),
+        video_metadata: None,
+    };
+    let mut parts = transform_pending_media_to_google(&context, vec![audio], 0).await?;
+    Ok(parts.remove(0))
+}
+
+// Gemini parts for the media of a request whose other content serializes to `request_len` bytes.
+// Small media goes inline in message order while the request stays under its size limit,
+// other media through the Files API unless uploaded before.
+async fn transform_pending_media_to_google(
+    context: &MediaContext<'_>,
+    pending: Vec<PendingMedia>,
+    request_len: usize,
+) -> Result<Vec<Value>, Error> {
+    let mut inline_budget = REQUEST_SIZE_BUDGET.saturating_sub(request_len);
+    let inline: Vec<bool> = pending.iter().map(|media| {
+        let encoded_len = media.data.len().div_ceil(3) * 4;
+        let fits = media.data.len() <= context.media.inline_max_bytes && encoded_len <= inline_budget;
+        if fits {
+            inline_budget -= encoded_len;
+        }
+        fits
+    }).collect();
+    try_join_all(pending.into_iter().zip(inline).map(|(media, inline)| async move {
+        let mut part = if inline {
+            json!({
+                "inline_data": {
+                    "mime_type": media.mime_type,
+                    "data": STANDARD.encode(&media.data)
+                }
+            })
+        } else {
+            json!({
+                "file_data": {
+                    "mime_type": media.mime_type,
+                    "file_uri": upload_media_to_google(context, media.data, &media.mime_type, &media.display_name).await?
                 }
+            })
+        };
+        if let Some(video_metadata) = media.video_metadata {
+            part["videoMetadata"] = video_metadata;
+        }
+        Ok::<Value, Error>(part)
+    })).await
+}
+
+// Files API URI of media, uploaded unless the same bytes were uploaded before
+async fn upload_media_to_google(context: &MediaContext<'_>, data: Vec<u8>, mime_type: &str, display_name: &str) -> Result<String, Error> {
+    let cache_key = UploadCache::key(context.api_key, &data);
+    if let Some(file_uri) = context.upload_cache.get(&cache_key) {
+        return Ok(file_uri);
+    }
+    let _slot = context.slot().await?;
+    let metadata = json!({"file": {"display_name": display_name}});
+    let (file_uri, expiration_time) = upload_to_google(context.client, context.api_key, metadata, data, mime_type).await?;
+    log::info!("{display_name} uploaded, URI: {file_uri}");
+    context.upload_cache.insert(cache_key, file_uri.clone(), expiration_time);
+    Ok(file_uri)
+}
+
+/// Per choice index part of StreamState
+#[derive(Default)]
+pub struct ChoiceState {
+    /// Number of tool calls already relayed
+    pub tool_call_count: usize,
+    /// Gemini finishReason once upstream reported one
+    pub finish_reason: Option<String>,
+    pub finish_reason_sent: bool,
+    pub role_sent: bool,
+}
+
+/// State carried between the chunks of one response, also used for non-stream replies
+pub struct StreamState {
+    /// Completion id shared by every chunk of the response
+    pub id: String,
+    /// Unix timestamp of the request
+    pub created: u64,
+    /// Whether the last relayed part was a thought
+    pub last_is_thought: bool,
+    pub choices: BTreeMap<usize, ChoiceState>,
+    /// Model name of the last relayed chunk, reused by the closing chunk
+    pub model: Value,
+    /// stream_options.include_usage of the request
+    pub include_usage: bool,
+    /// Last usage reported upstream, relayed once at the end of a stream
+    pub usage: Option<Value>,
+    /// Where the thought signatures of relayed function calls are kept
+    pub thought_signatures: Arc<ThoughtSignatureStore>,
+}
+
+impl StreamState {
+    pub fn new(id: String, created: u64, thought_signatures: Arc<ThoughtSignatureStore>) -> Self {
+        Self {
+            id,
+            created,
+            last_is_thought: false,
+            choices: BTreeMap::new(),
+            model: Value::Null,
+            include_usage: false,
+            usage: None,
+            thought_signatures,
+        }
+    }
+}
+
+/// Translate complete upstream SSE events into chat.completion.chunk values, upstream errors are kept as error objects
+pub fn transform_google_stream_to_openai(events: Vec<String>, no_thought_process: bool, thought_mode: ThoughtMode, image_output: ImageOutput, state: &mut StreamState) -> Vec<Value> {
+    let mut output = Vec::new();
+    for event in events {
+        log::info!("Got streaming event: {event}");
+        match serde_json::from_str::<Value>(&event) {
+            Ok(json) if json.get("error").is_some() => {
+                log::error!("Upstream stream reported an error: {json}");
+                output.push(json);
+            },
+            Ok(json) => {
+                if let Some(openai_chunk) = transform_google_to_openai(&json, true, no_thought_process, thought_mode, image_output, state) {
+                    output.push(openai_chunk);
+                }
+            },
+            Err(e) => log::error!("Failed to parse JSON: {e}"),
+        }
+    }
+    output
+}
+
+/// Closing chunks of an OpenAI stream once the upstream body has ended.
+/// A finishReason arriving without content is not relayed by transform_google_to_openai,
+/// so emit a last chunk for choices still lacking a finish_reason, then the usage-only chunk
+/// when stream_options.include_usage was requested.
+pub fn finish_google_stream_to_openai(state: &mut StreamState) -> Vec<Value> {
+    let mut output = Vec::new();
+    let pending_choices: Vec<Value> = state.choices.iter()
+        .filter(|(_, choice)| !choice.finish_reason_sent)
+        .map(|(index, choice)| json!({
+            "delta": {},
+            "finish_reason": choice.finish_reason.as_deref().map_or("stop".to_string(), |r| map_finish_reason(r, choice.tool_call_count > 0)),
+            "native_finish_reason": choice.finish_reason,
+            "index": index
+        }))
+        .collect();
+    for choice in state.choices.values_mut() {
+        choice.finish_reason_sent = true;
+    }
+    if !pending_choices.is_empty() {
+        output.push(json!({
+            "id": state.id,
+            "object": "chat.completion.chunk",
+            "created": state.created,
+            "model": state.model,
+            "choices": pending_choices
+        }));
+    }
+    if let Some(usage) = state.usage.take().filter(|_| state.include_usage) {
+        output.push(json!({
+            "id": state.id,
+            "object": "chat.completion.chunk",
+            "created": state.created,
+            "model": state.model,
+            "choices": [],
+            "usage": usage
+        }));
+    }
+    output
+}
+
+/// OpenAI style error object for a stream that cannot be continued
+pub fn openai_stream_error(message: &str) -> Value {
+    json!({
+        "error": {
+            "message": message,
+            "type": "upstream_error",
+            "param": null,
+            "code": null
+        }
+    })
+}
+
+// Map Gemini finishReason onto the OpenAI finish_reason vocabulary
+fn map_finish_reason(gemini_finish_reason: &str, has_tool_calls: bool) -> String {
+    if has_tool_calls {
+        return "tool_calls".to_string();
+    }
+    match gemini_finish_reason {
+        "MAX_TOKENS" => "length",
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII"
+        | "IMAGE_SAFETY" | "IMAGE_PROHIBITED_CONTENT" | "IMAGE_RECITATION" => "content_filter",
+        // STOP, OTHER, LANGUAGE, MALFORMED_FUNCTION_CALL... have no closer OpenAI equivalent
+        _ => "stop",
+    }.to_string()
+}
+
+// Sum the tokenCount of one modality in a Gemini ModalityTokenCount list
+fn modality_token_count(details: Option<&Value>, modality: &str) -> i64 {
+    details.and_then(Value::as_array).map_or(0, |details| {
+        details.iter()
+            .filter(|detail| detail.get("modality").and_then(Value::as_str) == Some(modality))
+            .filter_map(|detail| detail.get("tokenCount").and_then(Value::as_i64))
+            .sum()
+    })
+}
+
+// Convert Gemini usageMetadata to an OpenAI usage object, thought tokens count as completion tokens
+fn transform_google_usage_to_openai(usage_metadata: &Value) -> Option<Value> {
+    let count = |key: &str| usage_metadata.get(key).and_then(Value::as_i64).unwrap_or(0);
+    usage_metadata.get("promptTokenCount")?;
+    let prompt_tokens = count("promptTokenCount") + count("toolUsePromptTokenCount");
+    let reasoning_tokens = count("thoughtsTokenCount");
+    let completion_tokens = count("candidatesTokenCount") + reasoning_tokens;
+    let total_tokens = usage_metadata.get("totalTokenCount").and_then(Value::as_i64)
+        .unwrap_or(prompt_tokens + completion_tokens);
+    Some(json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": total_tokens,
+        "prompt_tokens_details": {
+            "cached_tokens": count("cachedContentTokenCount"),
+            "audio_tokens": modality_token_count(usage_metadata.get("promptTokensDetails"), "AUDIO")
+                + modality_token_count(usage_metadata.get("toolUsePromptTokensDetails"), "AUDIO")
+        },
+        "completion_tokens_details": {
+            "reasoning_tokens": reasoning_tokens,
+            "audio_tokens": modality_token_count(usage_metadata.get("candidatesTokensDetails"), "AUDIO")
+        }
+    }))
+}
+
+pub fn new_chat_completion_id() -> String {
+    format!("chatcmpl-{}", uuid::Uuid::new_v4().simple())
+}
+
+/// Prefix of the tool call ids minted for function calls that carry a thought signature
+const THOUGHT_SIGNATURE_ID_PREFIX: &str = "call_ts_";
+
+/// Thought signatures kept, the oldest are dropped first
+const THOUGHT_SIGNATURE_CAPACITY: usize = 10_000;
+
+/// Function call as Gemini sent it, kept under the tool call id given to the client
+#[derive(Clone)]
+pub struct StoredFunctionCall {
+    /// Gemini's functionCall.id, if it sent one
+    pub id: Option<String>,
+    pub thought_signature: String,
+}
+
+#[derive(Default)]
+struct ThoughtSignatureEntries {
+    calls: HashMap<String, StoredFunctionCall>,
+    order: VecDeque<String>,
+}
+
+/// Thought signatures of function calls by the tool call id given to the client.
+/// Signatures run to kilobytes, too long to ride inside ids that clients limit to 40 characters.
+#[derive(Default)]
+pub struct ThoughtSignatureStore {
+    entries: Mutex<ThoughtSignatureEntries>,
+}
+
+impl ThoughtSignatureStore {
+    /// Keep a function call's signature, returning the tool call id to give the client
+    pub fn insert(&self, function_call_id: Option<&str>, thought_signature: &str) -> String {
+        let id = format!("{THOUGHT_SIGNATURE_ID_PREFIX}{}", uuid::Uuid::new_v4().simple());
+        let mut entries = self.entries.lock().unwrap();
+        entries.calls.insert(id.clone(), StoredFunctionCall {
+            id: function_call_id.map(String::from),
+            thought_signature: thought_signature.to_string(),
+        });
+        entries.order.push_back(id.clone());
+        while entries.order.len() > THOUGHT_SIGNATURE_CAPACITY {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.calls.remove(&oldest);
             }
         }
+        id
+    }
+
+    /// The function call behind a tool call id from the client, None for ids not minted by `insert`
+    pub fn get(&self, tool_call_id: &str) -> Option<StoredFunctionCall> {
+        if !tool_call_id.starts_with(THOUGHT_SIGNATURE_ID_PREFIX) {
+            return None;
+        }
+        let stored = self.entries.lock().unwrap().calls.get(tool_call_id).cloned();
+        if stored.is_none() {
+            log::warn!("Thought signature of tool call {tool_call_id} is no longer kept, Gemini may refuse the replayed call");
+        }
+        stored
+    }
+}
+
+// Convert a Gemini functionCall part into an OpenAI tool_calls entry
+fn transform_google_function_call_to_openai(part: &Value, thought_signatures: &ThoughtSignatureStore) -> Value {
+    let function_call = &part["functionCall"];
+    let function_call_id = function_call.get("id").and_then(Value::as_str);
+    // Gemini 3 wants the thought signature back with the call, the client only echoes the id
+    let id = match part.get("thoughtSignature").and_then(Value::as_str) {
+        Some(thought_signature) => thought_signatures.insert(function_call_id, thought_signature),
+        None => function_call_id
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| format!("call_{}", uuid::Uuid::new_v4().simple())),
+    };
+    let arguments = function_call.get("args").cloned().unwrap_or(json!({}));
+    json!({
+        "id": id,
+        "type": "function",
+        "function": {
+            "name": function_call.get("name").and_then(Value::as_str).unwrap_or(""),
+            "arguments": arguments.to_string()
+        }
+    })
+}
+
+// Map OpenAI `tools` onto a single Gemini functionDeclarations tool
+fn transform_openai_tools_to_google(tools: &[Value]) -> Result<Option<Value>, Error> {
+    let mut function_declarations = Vec::new();
+    for function in tools.iter()
+        .filter(|tool| tool.get("type").and_then(Value::as_str).unwrap_or("function") == "function")
+        .filter_map(|tool| tool.get("function"))
+    {
+        let mut declaration = json!({
+            "name": function.get("name").cloned().unwrap_or(json!("")),
+        });
+        if let Some(description) = function.get("description") {
+            declaration["description"] = description.clone();
+        }
+        if let Some(parameters) = function.get("parameters") {
+            let parameters = json_schema_to_google(parameters)?;
+            // Gemini rejects OBJECT parameters without properties, no-argument tools go without
+            let no_properties = parameters.get("type").and_then(Value::as_str) == Some("object")
+                && parameters.get("properties").and_then(Value::as_object).is_none_or(|p| p.is_empty());
+            if !no_properties {
+                declaration["parameters"] = parameters;
+            }
+        }
+        function_declarations.push(declaration);
+    }
+    if function_declarations.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(json!([{ "functionDeclarations": function_declarations }])))
+    }
+}
+
+// Map OpenAI `response_format` onto Gemini generationConfig fields
+fn apply_response_format(response_format: &Value, generation_config: &mut Value) -> Result<(), Error> {
+    match response_format.get("type").and_then(Value::as_str) {
+        Some("json_object") => {
+            generation_config["responseMimeType"] = json!("application/json");
+        },
+        Some("json_schema") => {
+            generation_config["responseMimeType"] = json!("application/json");
+            if let Some(schema) = response_format.get("json_schema").and_then(|s| s.get("schema")) {
+                generation_config["responseSchema"] = json_schema_to_google(schema)?;
+            }
+        },
+        Some("text") | None => {},
+        Some(other) => return Err(ErrorBadRequest(format!("Unsupported response_format type: {other}"))),
+    }
+    Ok(())
+}
+
+// Map OpenAI `tool_choice` onto Gemini toolConfig.functionCallingConfig
+fn transform_openai_tool_choice_to_google(tool_choice: &Value) -> Option<Value> {
+    let function_calling_config = match tool_choice {
+        Value::String(mode) => match mode.as_str() {
+            "auto" => json!({ "mode": "AUTO" }),
+            "none" => json!({ "mode": "NONE" }),
+            "required" => json!({ "mode": "ANY" }),
+            _ => {
+                log::warn!("Unknown tool_choice: {mode}");
+                return None;
+            }
+        },
+        Value::Object(_) => {
+            let name = tool_choice.get("function").and_then(|f| f.get("name")).and_then(Value::as_str)?;
+            json!({ "mode": "ANY", "allowedFunctionNames": [name] })
+        },
+        _ => return None,
+    };
+    Some(json!({ "functionCallingConfig": function_calling_config }))
+}
 
-        Ok(Bytes::from(output.join("")))
+// Convert an OpenAI `tool` role message into a Gemini functionResponse part
+fn transform_openai_tool_message_to_google(msg: &Value, tool_call_names: &HashMap<String, String>, thought_signatures: &ThoughtSignatureStore) -> Value {
+    let tool_call_id = msg.get("tool_call_id").and_then(Value::as_str);
+    let name = tool_call_id
+        .and_then(|id| tool_call_names.get(id).cloned())
+        .or_else(|| msg.get("name").and_then(Value::as_str).map(|n| n.to_string()))
+        .unwrap_or_else(|| {
+            log::warn!("Tool message does not match any previous tool call");
+            String::new()
+        });
+    let content = match msg.get("content") {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(content_parts)) => content_parts.iter()
+            .filter_map(|part| part.get("text").and_then(Value::as_str))
+            .collect::<Vec<&str>>()
+            .join(""),
+        _ => String::new(),
+    };
+    // functionResponse.response must be an object
+    let response = match serde_json::from_str::<Value>(&content) {
+        Ok(json @ Value::Object(_)) => json,
+        _ => json!({ "content": content }),
+    };
+    let mut function_response = json!({
+        "name": name,
+        "response": response
     });
-    (data_r, last_is_thought)
+    if let Some(tool_call_id) = tool_call_id {
+        let id = thought_signatures.get(tool_call_id).and_then(|stored| stored.id);
+        function_response["id"] = json!(id.as_deref().unwrap_or(tool_call_id));
+    }
+    json!({ "functionResponse": function_response })
 }
 
 // This function should be updated to match the new requirements:
-pub fn transform_google_to_openai(body: &Value, stream_mode: bool, no_thought_process: bool, prev_thought: bool, md_thought: bool) -> (Option<Value>, bool) {
+pub fn transform_google_to_openai(body: &Value, stream_mode: bool, no_thought_process: bool, thought_mode: ThoughtMode, image_output: ImageOutput, state: &mut StreamState) -> Option<Value> {
+    let prev_thought = state.last_is_thought;
     let mut last_contains_thought = false;
     let mut empty_choices = true;
     let message_type = if stream_mode { "delta" } else { "message" };
     let converted_model_name= {
         let mut model_name = body.get("modelVersion").cloned().unwrap_or(json!(""));
         if no_thought_process && model_name.is_string() {
-            model_name = json!(format!("{}-no-thought-process", model_name.as_str().unwrap()));
+            model_name = json!(format!("{}{NO_THOUGHT_PROCESS_SUFFIX}", model_name.as_str().unwrap()));
         }
         model_name
     };
     let mut openai_response = json!({
-        "id": format!("chatcmpl-{}", uuid::Uuid::new_v4().to_string().replace("-", "")),
+        "id": state.id,
         "object": if stream_mode { "chat.completion.chunk" } else { "chat.completion" },
-        "created": body.get("created").cloned().unwrap_or(json!(0)),
+        "created": state.created,
         "model": converted_model_name,
         "choices": []
     });
-    if let Some(usage_metadata) = body.get("usageMetadata") {
-        if let (Some(prompt_token_count), Some(candidates_token_count), Some(total_token_count)) = (
-            usage_metadata.get("promptTokenCount"),
-            usage_metadata.get("candidatesTokenCount"),
-            usage_metadata.get("totalTokenCount")
-        ) {
-            openai_response["usage"] = json!({
-                "prompt_tokens": prompt_token_count,
-                "completion_tokens": candidates_token_count,
-                "total_tokens": total_token_count
-            });
+    if let Some(usage) = body.get("usageMetadata").and_then(transform_google_usage_to_openai) {
+        // Streams relay usage once in a trailing usage-only chunk
+        if stream_mode {
+            state.usage = Some(usage);
+        } else {
+            openai_response["usage"] = usage;
         }
     }
     if let Some(candidates) = body.get("candidates").and_then(Value::as_array) {
-        for (index, candidate) in candidates.iter().enumerate() {
+        for (position, candidate) in candidates.iter().enumerate() {
+            // With n > 1 a chunk may carry only some candidates, in any order
+            let index = candidate.get("index").and_then(Value::as_u64).map_or(position, |index| index as usize);
+            let choice_state = state.choices.entry(index).or_default();
+            if let Some(reason) = candidate.get("finishReason").and_then(|r| r.as_str()) {
+                choice_state.finish_reason = Some(reason.to_string());
+            }
             if let Some(content) = candidate.get("content") {
                 if let Some(parts) = content.get("parts").and_then(Value::as_array) {
                     let text_thought: Vec<(String, bool)> = parts.iter().filter_map(|part| {
@@ -182,59 +615,124 @@ pub fn transform_google_to_openai(body: &Value, stream_mode: bool, no_thought_pr
                         }
                     }).collect();
 
+                    let tool_call_count = &mut choice_state.tool_call_count;
+                    let tool_calls: Vec<Value> = parts.iter()
+                        .filter(|part| part.get("functionCall").is_some())
+                        .map(|part| {
+                            let mut tool_call = transform_google_function_call_to_openai(part, &state.thought_signatures);
+                            if stream_mode {
+                                // Stream accumulators merge tool call deltas by index
+                                tool_call["index"] = json!(*tool_call_count);
+                            }
+                            *tool_call_count += 1;
+                            tool_call
+                        })
+                        .collect();
+
+                    // Generated images arrive as inlineData parts
+                    let images: Vec<String> = parts.iter()
+                        .filter(|part| part.get("thought").and_then(Value::as_bool) != Some(true))
+                        .filter_map(|part| part.get("inlineData"))
+                        .filter_map(|inline_data| Some(format!(
+                            "data:{};base64,{}",
+                            inline_data.get("mimeType").and_then(Value::as_str).unwrap_or("image/png"),
+                            inline_data.get("data").and_then(Value::as_str)?
+                        )))
+                        .collect();
+
                     log::debug!("Google::candidates::content::parts::text.len() = {}", text_thought.len());
-                    let thought_start_str = if md_thought {
+                    let thought_start_str = if thought_mode == ThoughtMode::Markdown {
                         "## Thought process"
                     } else {
                         "<think>"
                     };
-                    let thought_end_str = if md_thought {
+                    let thought_end_str = if thought_mode == ThoughtMode::Markdown {
                         "## Answer after Thoughts"
                     } else {
                         "</think>"
                     };
-                    let text = match text_thought.len() {
-                        0 => continue,
-                        1 => {
-                            if no_thought_process {
-                                text_thought[0].0.clone()
-                            } else {
-                                match (prev_thought, last_contains_thought) {
-                                    (true, false) => format!("\n{}\n{}", thought_end_str, text_thought[0].0),
-                                    (false, true) => format!("{}\n{}", thought_start_str, text_thought[0].0),
-                                    _ => text_thought[0].0.clone(),
+                    let mut reasoning = None;
+                    let text = if thought_mode == ThoughtMode::ReasoningContent {
+                        if text_thought.is_empty() && tool_calls.is_empty() && images.is_empty() {
+                            continue;
+                        }
+                        let join_parts = |thought: bool| {
+                            let joined: String = text_thought.iter().filter(|t| t.1 == thought).map(|t| t.0.as_str()).collect();
+                            Some(joined).filter(|j| !j.is_empty())
+                        };
+                        reasoning = join_parts(true);
+                        join_parts(false)
+                    } else {
+                        match text_thought.len() {
+                            0 if tool_calls.is_empty() && images.is_empty() => continue,
+                            0 => None,
+                            1 => Some({
+                                if no_thought_process {
+                                    text_thought[0].0.clone()
+                                } else {
+                                    match (prev_thought, last_contains_thought) {
+                                        (true, false) => format!("\n{}\n{}", thought_end_str, text_thought[0].0),
+                                        (false, true) => format!("{}\n{}", thought_start_str, text_thought[0].0),
+                                        _ => text_thought[0].0.clone(),
+                                    }
                                 }
-                            }
-                        },
-                        2 => {
-                            if text_thought[0].1 && !text_thought[1].1 {
-                                format!("{}\n{}\n{}", text_thought[0].0, thought_end_str, text_thought[1].0)
-                            } else {
-                                format!("{}{}", text_thought[0].0, text_thought[1].0)
-                            }
-                        },
-                        _ => {
-                            let mut formatted_text = String::new();
-                            for (i, t) in text_thought.iter().enumerate() {
-                                formatted_text.push_str(&format!("## Part {}(Thought: {})\n{}\n", i + 1, t.1, t.0));
-                            }
-                            formatted_text
+                            }),
+                            2 => Some({
+                                if text_thought[0].1 && !text_thought[1].1 {
+                                    format!("{}\n{}\n{}", text_thought[0].0, thought_end_str, text_thought[1].0)
+                                } else {
+                                    format!("{}{}", text_thought[0].0, text_thought[1].0)
+                                }
+                            }),
+                            _ => Some({
+                                let mut formatted_text = String::new();
+                                for (i, t) in text_thought.iter().enumerate() {
+                                    formatted_text.push_str(&format!("## Part {}(Thought: {})\n{}\n", i + 1, t.1, t.0));
+                                }
+                                formatted_text
+                            })
+                        }
+                    };
+                    let text = if image_output == ImageOutput::Markdown && !images.is_empty() {
+                        let mut text = text.unwrap_or_default();
+                        // An image-only part also ends a thought block
+                        if text_thought.is_empty() && prev_thought && !no_thought_process && thought_mode != ThoughtMode::ReasoningContent {
+                            text = format!("\n{thought_end_str}\n");
                         }
+                        let markdown: String = images.iter().map(|url| format!("\n![image]({url})\n")).collect();
+                        Some(text + &markdown)
+                    } else {
+                        text
                     };
                 
                     // Construct the message object dynamically
                     let mut message = json!({
                         "content": text
                     });
+                    if let Some(reasoning) = reasoning {
+                        message["reasoning_content"] = json!(reasoning);
+                    }
+                    if !tool_calls.is_empty() {
+                        message["tool_calls"] = json!(tool_calls);
+                    }
+                    if image_output == ImageOutput::Images && !images.is_empty() {
+                        message["images"] = json!(images.iter().map(|url| json!({ "type": "image_url", "image_url": { "url": url } })).collect::<Vec<Value>>());
+                    }
                     
-                    if let Some(role) = parts.first().and_then(|part| part.get("role").and_then(|r| r.as_str())) {
-                        let role = if role == "model" { "assistant" } else { role };
-                        message["role"] = json!(role);
+                    // Only the first delta of a choice carries the role
+                    if !stream_mode || !choice_state.role_sent {
+                        message["role"] = json!("assistant");
+                        choice_state.role_sent = true;
                     }
 
+                    let native_finish_reason = candidate.get("finishReason").and_then(|r| r.as_str());
+                    let finish_reason = native_finish_reason.map(|r| map_finish_reason(r, choice_state.tool_call_count > 0));
+                    choice_state.finish_reason_sent |= finish_reason.is_some();
+                    // native_finish_reason keeps the untranslated Gemini value
                     openai_response["choices"].as_array_mut().unwrap().push(json!({
                         message_type: message,
-                        "finish_reason": candidate.get("finishReason").and_then(|r| r.as_str()).map(|r| r.to_lowercase()),
+                        "finish_reason": finish_reason,
+                        "native_finish_reason": native_finish_reason,
                         "index": index
                     }));
 
@@ -243,86 +741,258 @@ pub fn transform_google_to_openai(body: &Value, stream_mode: bool, no_thought_pr
             }
         }
     }
+    state.last_is_thought = last_contains_thought;
+    state.model = openai_response["model"].clone();
     if empty_choices {
-        (None, last_contains_thought)
+        None
+    } else {
+        Some(openai_response)
+    }
+}
+
+// Gemini part for a media URL: data URIs and http(s) URLs carry the media, gs:// and Files API URIs are referenced directly
+async fn transform_media_url_to_google(url: &str, image_only: bool, context: &MediaContext<'_>) -> Result<GooglePart, Error> {
+    let (mime_type, data) = if url.starts_with("data:") {
+        decode_base64_and_get_mime_type(url)?
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        if url.starts_with(GOOGLE_FILES_URI_PREFIX) {
+            return Ok(GooglePart::Ready(json!({ "file_data": { "file_uri": url } })));
+        }
+        let _slot = context.slot().await?;
+        context.media.fetch_remote_media(context.media_client, url, image_only).await?
+    } else if url.starts_with("gs://") {
+        let mime_type = mime_type_from_extension(url)
+            .ok_or_else(|| ErrorBadRequest(format!("Cannot tell the MIME type of {url} from its extension")))?;
+        return Ok(GooglePart::Ready(json!({ "file_data": { "mime_type": mime_type, "file_uri": url } })));
+    } else if let Some(file_name) = url.strip_prefix("files/") {
+        return Ok(GooglePart::Ready(json!({ "file_data": { "file_uri": format!("{GOOGLE_FILES_URI_PREFIX}{file_name}") } })));
+    } else {
+        return Err(ErrorBadRequest("Media URLs must be data URIs, http(s) URLs, gs:// URIs or files/ names"));
+    };
+    let display_name = if mime_type.starts_with("image/") { "uploaded_image" } else { "uploaded_file" };
+    Ok(GooglePart::Media(PendingMedia { data, mime_type, display_name: display_name.to_string(), video_metadata: None }))
+}
+
+// Gemini part for an OpenAI file part, { file_data } as a data URI, raw base64 named by filename or a URL, or { file_id }
+async fn transform_openai_file_to_google(file: &Value, context: &MediaContext<'_>) -> Result<GooglePart, Error> {
+    let filename = file.get("filename").and_then(Value::as_str);
+    if let Some(file_data) = file.get("file_data").and_then(Value::as_str) {
+        if file_data.contains("://") || file_data.starts_with("data:") || file_data.starts_with("files/") {
+            return transform_media_url_to_google(file_data, false, context).await;
+        }
+        let mime_type = filename.and_then(mime_type_from_extension)
+            .ok_or_else(|| ErrorBadRequest("file_data without a data URI needs a filename with a known extension"))?;
+        let data = STANDARD.decode(file_data).map_err(|_| ErrorBadRequest("Failed to decode Base64 data"))?;
+        let display_name = filename.unwrap_or("uploaded_file").to_string();
+        return Ok(GooglePart::Media(PendingMedia { data, mime_type: mime_type.to_string(), display_name, video_metadata: None }));
+    }
+    let file_id = file.get("file_id").and_then(Value::as_str)
+        .ok_or_else(|| ErrorBadRequest("file part needs file_data or file_id"))?;
+    let file_uri = if file_id.starts_with(GOOGLE_FILES_URI_PREFIX) {
+        file_id.to_string()
     } else {
-        (Some(openai_response), last_contains_thought)
+        format!("{GOOGLE_FILES_URI_PREFIX}{}", file_id.trim_start_matches("files/"))
+    };
+    let mut file_data = json!({ "file_uri": file_uri });
+    if let Some(mime_type) = filename.and_then(mime_type_from_extension) {
+        file_data["mime_type"] = json!(mime_type);
+    }
+    Ok(GooglePart::Ready(json!({ "file_data": file_data })))
+}
+
+// Gemini part for an OpenAI input_audio, { data, format } with raw base64 as the SDKs send it, or a data URI
+async fn transform_openai_input_audio_to_google(audio: &Value, context: &MediaContext<'_>) -> Result<GooglePart, Error> {
+    let data = audio.get("data").and_then(Value::as_str)
+        .ok_or_else(|| ErrorBadRequest("input_audio without data"))?;
+    let (mime_type, audio_data) = if data.starts_with("data:") {
+        decode_base64_and_get_mime_type(data)?
+    } else {
+        let format = audio.get("format").and_then(Value::as_str)
+            .ok_or_else(|| ErrorBadRequest("input_audio needs a format unless data is a data URI"))?;
+        let audio_data = STANDARD.decode(data).map_err(|_| ErrorBadRequest("Failed to decode Base64 data"))?;
+        if format == "pcm16" {
+            // OpenAI's raw pcm16 is 24 kHz mono little-endian, Gemini needs a container
+            ("audio/wav".to_string(), pcm_to_wav(&audio_data, 24_000))
+        } else {
+            let mime_type = audio_mime_type_from_format(format)
+                .ok_or_else(|| ErrorBadRequest(format!("Unsupported input_audio format: {format}")))?;
+            (mime_type.to_string(), audio_data)
+        }
+    };
+    let (mime_type, data) = {
+        let _slot = context.slot().await?;
+        context.media.prepare_audio(mime_type, audio_data).await?
+    };
+    Ok(GooglePart::Media(PendingMedia { data, mime_type, display_name: "uploaded_audio".to_string(), video_metadata: None }))
+}
+
+// Gemini videoMetadata from the `video_metadata` extension field of a part; offsets are seconds or Duration strings like "12.5s"
+fn transform_video_metadata_to_google(video_metadata: &Value) -> Result<Value, Error> {
+    let mut google_metadata = json!({});
+    for (field, google_field) in [("start_offset", "startOffset"), ("end_offset", "endOffset")] {
+        match video_metadata.get(field).or_else(|| video_metadata.get(google_field)) {
+            Some(Value::Number(seconds)) => google_metadata[google_field] = json!(format!("{seconds}s")),
+            Some(Value::String(duration)) => google_metadata[google_field] = json!(duration),
+            Some(_) => return Err(ErrorBadRequest(format!("video_metadata.{field} must be seconds or a duration string"))),
+            None => {},
+        }
+    }
+    if let Some(fps) = video_metadata.get("fps") {
+        google_metadata["fps"] = json!(fps.as_f64().ok_or_else(|| ErrorBadRequest("video_metadata.fps must be a number"))?);
     }
+    Ok(google_metadata)
 }
 
-pub async fn transform_openai_to_google(body: &Value, client: &Client, api_key: &str, thinking_config: &ThinkingConfig) -> Value {
+// Gemini part for one part of an OpenAI message content array
+async fn transform_openai_content_part_to_google(part: &Value, context: &MediaContext<'_>) -> Result<Option<GooglePart>, Error> {
+    match part.get("type").and_then(Value::as_str) {
+        Some("text") => Ok(part.get("text").and_then(Value::as_str).map(|text| GooglePart::Ready(json!({ "text": text })))),
+        Some("image_url") => {
+            // Both { url } objects and bare strings are seen in the wild
+            let url = part.get("image_url")
+                .and_then(|image_url| image_url.get("url").unwrap_or(image_url).as_str())
+                .ok_or_else(|| ErrorBadRequest("image_url part without a url"))?;
+            let image_part = transform_media_url_to_google(url, true, context).await.map_err(|e| {
+                log::error!("Error using image: {e}");
+                e
+            })?;
+            Ok(Some(image_part))
+        },
+        Some("input_audio") => {
+            let audio = part.get("input_audio").ok_or_else(|| ErrorBadRequest("input_audio part without input_audio"))?;
+            let audio_part = transform_openai_input_audio_to_google(audio, context).await.map_err(|e| {
+                log::error!("Error using audio: {e}");
+                e
+            })?;
+            Ok(Some(audio_part))
+        },
+        Some("file") => {
+            let file = part.get("file").ok_or_else(|| ErrorBadRequest("file part without a file"))?;
+            let mut file_part = transform_openai_file_to_google(file, context).await.map_err(|e| {
+                log::error!("Error using file: {e}");
+                e
+            })?;
+            if let Some(video_metadata) = part.get("video_metadata").or_else(|| file.get("video_metadata")) {
+                let video_metadata = transform_video_metadata_to_google(video_metadata)?;
+                match &mut file_part {
+                    GooglePart::Ready(file_part) => file_part["videoMetadata"] = video_metadata,
+                    GooglePart::Media(media) => media.video_metadata = Some(video_metadata),
+                }
+            }
+            Ok(Some(file_part))
+        },
+        _ => {
+            // Handle unknown types or log an error
+            log::warn!("Unknown content type in message");
+            Ok(None)
+        }
+    }
+}
+
+pub async fn transform_openai_to_google(
+    body: &Value,
+    client: &Client,
+    media_client: &MediaClient,
+    api_key: &str,
+    thinking_config: &ThinkingConfig,
+    data: &AppState,
+) -> Result<Value, Error> {
     let empty = vec![];
     let messages = body.get("messages").and_then(Value::as_array).unwrap_or(&empty);
 
-    let mut contents = Vec::new();
-    for msg in messages {
+    // Content parts of every message are converted concurrently, downloads and transcodes are the slow part of a request
+    let context = &MediaContext::new(client, media_client, api_key, data);
+    let converted_parts = try_join_all(messages.iter().map(|msg| async move {
+        let role = msg.get("role").and_then(Value::as_str);
+        match msg.get("content") {
+            Some(Value::Array(content_parts)) if role != Some("system") && role != Some("tool") => {
+                let parts = try_join_all(content_parts.iter().map(|part| transform_openai_content_part_to_google(part, context))).await?;
+                Ok::<Vec<GooglePart>, Error>(parts.into_iter().flatten().collect())
+            },
+            _ => Ok(Vec::new()),
+        }
+    })).await?;
+
+    let mut contents: Vec<Value> = Vec::new();
+    // Media parts by content and part index, filled in once the rest of the request is built
+    let mut pending_media = Vec::new();
+    let mut tool_call_names = HashMap::new();
+    let mut last_is_tool_response = false;
+    for (msg, converted_parts) in messages.iter().zip(converted_parts) {
         let role = msg.get("role").and_then(|r| r.as_str()).unwrap_or("user");
         let role = if role == "assistant" { "model" } else { role };
         if role == "system" {
             continue;
         }
+        if role == "tool" {
+            // Consecutive tool results answer the same model turn, keep them in one content
+            let part = transform_openai_tool_message_to_google(msg, &tool_call_names, &data.thought_signatures);
+            match contents.last_mut() {
+                Some(last) if last_is_tool_response => last["parts"].as_array_mut().unwrap().push(part),
+                _ => contents.push(json!({
+                    "role": "user",
+                    "parts": [part]
+                })),
+            }
+            last_is_tool_response = true;
+            continue;
+        }
+        last_is_tool_response = false;
 
         let content = msg.get("content").unwrap_or(&Value::Null);
-        let parts = match content {
+        let mut parts = match content {
             Value::String(text) => {
                 vec![json!({ "text": text })]
             },
-            Value::Array(content_parts) => {
-                let mut parts_vec = Vec::new();
-                for part in content_parts {
-                    match part.get("type").and_then(Value::as_str) {
-                        Some("text") => {
-                            if let Some(text) = part.get("text").and_then(Value::as_str) {
-                                parts_vec.push(json!({ "text": text }));
-                            }
-                        },
-                        Some("image_url") => {
-                            if let Some(image_url) = part.get("image_url") {
-                                let base64_data = image_url["url"].as_str().unwrap_or("");
-                                match upload_base64_image_to_google(client, api_key, base64_data).await {
-                                    Ok((mime, uploaded_uri)) => {
-                                        log::info!("image uploaded, URI: {uploaded_uri}");
-                                        parts_vec.push(json!({
-                                            "file_data": {
-                                                "mime_type": mime, // Adjust based on actual MIME type
-                                                "file_uri": uploaded_uri
-                                            }
-                                        }));
-                                    },
-                                    Err(e) => {
-                                        log::error!("Error uploading image: {e}");
-                                    }
-                                }
-                            }
-                        },
-                        Some("input_audio") => {
-                            if let Some(audio) = part.get("input_audio") {
-                                let base64_data = audio["data"].as_str().unwrap_or("");
-                                let _format = audio["format"].as_str().unwrap_or("wav");
-                                if let Ok((mime, uploaded_uri)) = upload_base64_audio_to_google(client, api_key, base64_data).await {
-                                    log::info!("audio uploaded, URI: {uploaded_uri}");
-                                    parts_vec.push(json!({
-                                        "file_data": {
-                                            "mime_type": mime, // Adjust MIME type as needed
-                                            "file_uri": uploaded_uri
-                                        }
-                                    }));
-                                } else {
-                                    log::error!("Error uploading audio");
-                                }
-                            }
-                        },
-                        _ => {
-                            // Handle unknown types or log an error
-                            log::warn!("Unknown content type in message");
-                        }
-                    }
-                }
-                parts_vec
+            Value::Array(_) => {
+                let content_index = contents.len();
+                converted_parts.into_iter().enumerate().map(|(part_index, part)| match part {
+                    GooglePart::Ready(part) => part,
+                    GooglePart::Media(media) => {
+                        pending_media.push(((content_index, part_index), media));
+                        json!({})
+                    },
+                }).collect()
             },
             _ => Vec::new(),
         };
 
+        if let Some(tool_calls) = msg.get("tool_calls").and_then(Value::as_array) {
+            for tool_call in tool_calls {
+                let function = &tool_call["function"];
+                let name = function["name"].as_str().unwrap_or("");
+                let id = tool_call.get("id").and_then(Value::as_str);
+                if let Some(id) = id {
+                    tool_call_names.insert(id.to_string(), name.to_string());
+                }
+                let args = match function.get("arguments") {
+                    Some(Value::String(arguments)) => serde_json::from_str(arguments).unwrap_or_else(|e| {
+                        log::warn!("Failed to parse tool call arguments: {e}");
+                        json!({})
+                    }),
+                    Some(arguments @ Value::Object(_)) => arguments.clone(),
+                    _ => json!({}),
+                };
+                let mut part = json!({
+                    "functionCall": {
+                        "name": name,
+                        "args": args
+                    }
+                });
+                if let Some(id) = id {
+                    // Gemini 3 rejects function calls replayed without their thought signature
+                    match data.thought_signatures.get(id) {
+                        Some(stored) => {
+                            part["functionCall"]["id"] = json!(stored.id.as_deref().unwrap_or(id));
+                            part["thoughtSignature"] = json!(stored.thought_signature);
+                        }
+                        None => part["functionCall"]["id"] = json!(id),
+                    }
+                }
+                parts.push(part);
+            }
+        }
+
         contents.push(json!({
             "role": role,
             "parts": parts
@@ -389,6 +1059,20 @@ pub async fn transform_openai_to_google(body: &Value, client: &Client, api_key:
             })
         };
     }
+    if let Some(response_format) = body.get("response_format") {
+        apply_response_format(response_format, &mut generation_config)?;
+    }
+    if let Some(modalities) = body.get("modalities").and_then(Value::as_array) {
+        // Audio is spoken from the text reply afterwards by a TTS model, see speak_chat_completion
+        let response_modalities: Vec<String> = modalities.iter()
+            .filter_map(Value::as_str)
+            .filter(|modality| *modality != "audio")
+            .map(|modality| modality.to_uppercase())
+            .collect();
+        if !response_modalities.is_empty() {
+            generation_config["responseModalities"] = json!(response_modalities);
+        }
+    }
     log::debug!("thinking_enabled: {}, thinking_budget: {:?}, generation_config: {}", thinking_config.enabled, thinking_config.budget, generation_config);
     
     let mut result = json!({
@@ -396,11 +1080,66 @@ pub async fn transform_openai_to_google(body: &Value, client: &Client, api_key:
         "safetySettings": safety_settings,
         "generationConfig": generation_config,
     });
+    if let Some(tools) = body.get("tools").and_then(Value::as_array) {
+        if let Some(tools) = transform_openai_tools_to_google(tools)? {
+            result["tools"] = tools;
+        }
+    }
+    if let Some(tool_config) = body.get("tool_choice").and_then(transform_openai_tool_choice_to_google) {
+        result["toolConfig"] = tool_config;
+    }
     if let Some(instruction) = system_instruction {
         result["systemInstruction"] = json!({
             "parts": [{"text": instruction}],
             "role": "system"
         });
     }
-    result
+    let (positions, pending_media): (Vec<_>, Vec<_>) = pending_media.into_iter().unzip();
+    let media_parts = transform_pending_media_to_google(context, pending_media, result.to_string().len()).await?;
+    for ((content_index, part_index), media_part) in positions.into_iter().zip(media_parts) {
+        result["contents"][content_index]["parts"][part_index] = media_part;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_thought_signatures_out_of_tool_call_ids() {
+        let thought_signatures = ThoughtSignatureStore::default();
+        let signature = STANDARD.encode((0..3000).map(|i| (i % 251) as u8).collect::<Vec<u8>>());
+        let part = json!({
+            "functionCall": {"id": "fc_1", "name": "get_weather", "args": {"city": "Paris"}},
+            "thoughtSignature": signature
+        });
+        let tool_call = transform_google_function_call_to_openai(&part, &thought_signatures);
+        let id = tool_call["id"].as_str().unwrap();
+        assert!(id.len() <= 40, "{id} is longer than OpenAI allows");
+
+        let stored = thought_signatures.get(id).unwrap();
+        assert_eq!(stored.id.as_deref(), Some("fc_1"));
+        assert_eq!(stored.thought_signature, signature);
+
+        // Calls without a signature keep Gemini's id, unknown ids resolve to nothing
+        let tool_call = transform_google_function_call_to_openai(&json!({"functionCall": {"id": "fc_2", "name": "f"}}), &thought_signatures);
+        assert_eq!(tool_call["id"], "fc_2");
+        assert!(thought_signatures.get("fc_2").is_none());
+        assert!(thought_signatures.get("call_ts_0123456789abcdef0123456789abcdef").is_none());
+    }
+
+    #[test]
+    fn keys_choices_by_candidate_index() {
+        let mut state = StreamState::new(new_chat_completion_id(), 0, Default::default());
+        let call = |index: u64, name: &str| json!({
+            "candidates": [{"content": {"parts": [{"functionCall": {"name": name, "args": {}}}], "role": "model"}, "index": index}]
+        });
+        for (chunk, choice_index) in [(call(1, "a"), 1), (call(0, "b"), 0), (call(1, "c"), 1)] {
+            let openai_chunk = transform_google_to_openai(&chunk, true, false, ThoughtMode::Think, ImageOutput::Markdown, &mut state).unwrap();
+            assert_eq!(openai_chunk["choices"][0]["index"], choice_index);
+        }
+        assert_eq!(state.choices[&0].tool_call_count, 1);
+        assert_eq!(state.choices[&1].tool_call_count, 2);
+    }
 }
@@ -1,7 +1,8 @@
-use actix_web::HttpRequest;
-use awc::{Client, Connector};
+use actix_web::{error::ErrorInternalServerError, Error, HttpRequest, HttpResponse};
+use awc::{Client, ClientRequest, Connector};
 use rustls::ClientConfig;
 use rustls_platform_verifier::BuilderVerifierExt;
+use serde_json::Value;
 use std::sync::Arc;
 
 pub fn tls_config() -> ClientConfig {
@@ -38,9 +39,43 @@ pub fn extract_api_key(req: &HttpRequest) -> Option<String> {
             // Use strip_prefix instead of starts_with and manual slicing
             auth.strip_prefix("Bearer ").map(|stripped| stripped.to_string())
         })
+        .or_else(|| {
+            // Anthropic SDKs send the key in x-api-key
+            req.headers()
+                .get("x-api-key")
+                .and_then(|h| h.to_str().ok())
+                .map(String::from)
+        })
         .or_else(|| {
             req.uri()
                 .query()
                 .and_then(|q| q.split('=').nth(1).map(String::from))
         })
 }
+
+/// Non-stream Gemini replies stay far below this, even with inline media
+pub const GOOGLE_JSON_BODY_LIMIT: usize = 64 << 20;
+
+/// Send a request to a Gemini JSON endpoint and parse the reply.
+/// An unsuccessful upstream reply is returned as `Ok(Err(response))` so it can be relayed to the client as is.
+pub async fn send_google_json(request: ClientRequest, body: Option<&Value>) -> Result<Result<Value, HttpResponse>, Error> {
+    log::info!("Forwarding request to: {}", request.get_uri());
+    let request = request.timeout(std::time::Duration::from_secs(600));
+    let sent = match body {
+        Some(body) => request.send_json(body).await,
+        None => request.send().await,
+    };
+    let mut upstream_response = sent.map_err(|e| {
+        log::error!("Failed to forward request: {e:?}");
+        ErrorInternalServerError("Failed to connect to upstream server.")
+    })?;
+    let body = upstream_response.body().limit(GOOGLE_JSON_BODY_LIMIT).await?;
+    let status = upstream_response.status();
+    if !status.is_success() {
+        log::error!("Google replied {status}: {}", String::from_utf8_lossy(&body));
+        return Ok(Err(HttpResponse::build(status).content_type("application/json").body(body)));
+    }
+    serde_json::from_slice(&body)
+        .map(Ok)
+        .map_err(|_| ErrorInternalServerError("Failed to parse Google response"))
+}